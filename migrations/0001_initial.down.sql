@@ -0,0 +1,4 @@
+drop table if exists admins;
+drop table if exists employees;
+drop table if exists users;
+drop table if exists articles;
@@ -0,0 +1,31 @@
+create table if not exists articles
+(
+    id serial primary key not null,
+    path text not null,
+    title text not null,
+    cdate date not null,
+    udate date,
+    author text
+);
+
+create table if not exists users
+(
+    id serial primary key not null,
+    username text not null,
+    pwhash text not null,
+    email text not null,
+    firstname text,
+    lastname text
+);
+
+create table if not exists employees
+(
+    id serial primary key not null,
+    uid integer references users (id) not null
+);
+
+create table if not exists admins
+(
+    id serial primary key not null,
+    uid integer references users (id) not null
+);
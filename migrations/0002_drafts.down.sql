@@ -0,0 +1,7 @@
+drop table if exists drafts;
+
+alter table articles
+    drop constraint if exists articles_author_fkey;
+
+alter table articles
+    alter column author type text using author::text;
@@ -0,0 +1,13 @@
+alter table articles
+    alter column author type integer using author::integer;
+
+alter table articles
+    add constraint articles_author_fkey foreign key (author) references users (id);
+
+create table if not exists drafts
+(
+    id serial primary key not null,
+    path text not null,
+    title text,
+    author integer references users (id) not null
+);
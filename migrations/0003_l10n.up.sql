@@ -0,0 +1,17 @@
+create table if not exists l10n
+(
+    code text primary key not null,
+    path text not null
+);
+
+insert into l10n (code, path)
+    select 'de', 'public/l10n/de.ron'
+    where not exists (select 1 from l10n where code = 'de');
+
+insert into l10n (code, path)
+    select 'en', 'public/l10n/en.ron'
+    where not exists (select 1 from l10n where code = 'en');
+
+insert into l10n (code, path)
+    select 'pl', 'public/l10n/pl.ron'
+    where not exists (select 1 from l10n where code = 'pl');
@@ -0,0 +1,12 @@
+create table if not exists sessions
+(
+    id text primary key not null,
+    uid integer references users (id) on delete cascade not null,
+    created timestamp not null default now(),
+    expires timestamp not null,
+    last_seen timestamp not null default now(),
+    ip text,
+    user_agent text
+);
+
+create index if not exists sessions_uid on sessions (uid);
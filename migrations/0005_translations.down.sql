@@ -0,0 +1,9 @@
+drop index if exists articles_translation;
+
+delete from articles where original is not null;
+
+alter table articles
+    drop column if exists original;
+
+alter table articles
+    drop column if exists lang;
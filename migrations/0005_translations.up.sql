@@ -0,0 +1,11 @@
+-- articles without a language were written before translations existed and
+-- count as the configured default language
+alter table articles
+    add column if not exists lang text;
+
+-- translations point to the article they translate, which points nowhere
+alter table articles
+    add column if not exists original integer references articles (id) on delete cascade;
+
+create unique index if not exists articles_translation
+    on articles (coalesce(original, id), lang);
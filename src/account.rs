@@ -1,6 +1,5 @@
 use std::path::Path;
 
-use actix_http::HttpMessage;
 use actix_web::{get, http, post, web, HttpRequest, HttpResponse, Responder};
 use tokio::fs;
 
@@ -8,14 +7,22 @@ use actix_identity::Identity;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::error::Result;
-use crate::template;
+use crate::error::{Error, Result};
+use crate::i18n::Lang;
+use crate::session;
+use crate::template::{self, RenderContext};
 use crate::web::ServerData;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ArticleData {
     title: String,
     article: String,
+    // left out by older forms, which publish in the default language
+    #[serde(default)]
+    lang: Option<String>,
+    // the id of the article this one translates, empty if it's none
+    #[serde(default)]
+    original: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,22 +49,22 @@ pub struct SetEmployeeData {
     uid: i32,
 }
 
-fn pathify(string: &str) -> (String, String) {
+fn pathify(root: &Path, string: &str) -> (String, String) {
     let public_path = format!(
         "articles/{}",
         string.replace(|ch: char| !ch.is_alphanumeric(), "-")
     );
-    let private_path = format!("public/{}", &public_path);
+    let private_path = root.join(&public_path).to_string_lossy().to_string();
     (public_path, private_path)
 }
 
-fn draftify(user: &str, string: &str) -> String {
+fn draftify(root: &Path, user: &str, string: &str) -> String {
     let public_path = format!(
         "drafts/{}",
         string.replace(|ch: char| !ch.is_alphanumeric(), "-")
     );
-    let private_path = format!("private/{}/{}", user, &public_path);
-    private_path
+    let private_path = root.join(user).join(&public_path);
+    private_path.to_string_lossy().to_string()
 }
 
 #[get("/account/me.html")]
@@ -65,34 +72,16 @@ pub async fn me<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if identity.identity().is_some() {
-        let mut body = fs::read_to_string("public/account/me.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    if cx.user().await?.is_some() {
+        let body = template::render(&cx, data.config.public.join("account/me.html")).await?;
         Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "text/html")
             .body(body))
     } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
+        let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
         Ok(HttpResponse::Forbidden().body(body))
     }
 }
@@ -102,53 +91,20 @@ pub async fn admin_panel<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if let Some(username) = identity.identity() {
-        let admin = data.client.query_opt(
-            "select id from admins where uid = \
-             (select id as uid from users where username = $1)",
-            &[&username]
-        ).await?;
-        if admin.is_none() {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
-            return Ok(HttpResponse::Forbidden().body(body));
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) if user.admin => {
+            let body = template::render(&cx, data.config.public.join("account/admin.html")).await?;
+            Ok(HttpResponse::Ok()
+                .header(http::header::CONTENT_TYPE, "text/html")
+                .body(body))
+        }
+        _ => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
+            Ok(HttpResponse::Forbidden().body(body))
         }
-
-        let mut body = fs::read_to_string("public/account/admin.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
-        Ok(HttpResponse::Ok()
-            .header(http::header::CONTENT_TYPE, "text/html")
-            .body(body))
-    } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
-        Ok(HttpResponse::Forbidden().body(body))
     }
 }
 
@@ -158,61 +114,39 @@ pub async fn save<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if let Some(username) = identity.identity() {
-        let uid = data
-            .client
-            .query_one(
-                "select users.id as uid from users where username = $1",
-                &[&username],
-            )
-            .await?;
-        let uid = uid.get::<_, i32>("uid");
-        let user = data
-            .client
-            .query_opt(
-                "select employees.id from employees where employees.uid = $1",
-                &[&uid],
-            )
-            .await?;
-        if user.is_some() {
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) if user.employee => {
             let draft_data = draft_data.into_inner();
             let title = draft_data.title;
             let article = draft_data.article;
             if draft_data.delete {
-                let mut private = draftify(&username, &title);
+                let mut private = draftify(&data.config.private, &user.username, &title);
                 private.push_str(".md");
-                data
-                    .client
+                cx.client()
+                    .await?
                     .execute(
                         "delete from drafts where path = $2 and title = $1 and author = $3",
-                        &[&title, &private, &uid],
+                        &[&title, &private, &user.id],
                     )
                     .await?;
             } else {
-                let mut private = draftify(&username, &title);
+                let mut private = draftify(&data.config.private, &user.username, &title);
                 private.push_str(".md");
-                let existing = data
-                    .client
+                let existing = cx
+                    .client()
+                    .await?
                     .query(
                         "select * from drafts where path = $2 and title != $1 and author = $3",
-                        &[&title, &private, &uid],
+                        &[&title, &private, &user.id],
                     )
                     .await?;
                 if !existing.is_empty() {
-                    let mut body = fs::read_to_string("private/exists.html").await?;
-                    template::search_replace_recursive(
-                        &identity,
-                        &data.client,
-                        &data.lang[&lang],
-                        &mut body,
-                        &[format!("article {}", title)],
-                    )
-                    .await?;
+                    let cx = cx.with_args(vec![format!("article {}", title)]);
+                    let body =
+                        template::render(&cx, data.config.private.join("exists.html")).await?;
                     return Ok(HttpResponse::BadRequest().body(body));
                 }
                 let path: &Path = private.as_ref();
@@ -221,201 +155,181 @@ pub async fn save<'a>(
                     .expect("`draftify()` didn't return a proper path");
                 fs::create_dir_all(directory).await?;
                 fs::write(&private, article).await?;
-                let existing = data
-                    .client
-                    .query_opt(
-                        "select id from drafts where path = $1",
-                        &[&private],
-                    )
+                let existing = cx
+                    .client()
+                    .await?
+                    .query_opt("select id from drafts where path = $1", &[&private])
                     .await?;
                 if let Some(row) = existing {
                     let id = row.get::<_, i32>("id");
-                    data.client
-                        .execute(
-                            "update drafts set title = $1 where id = $2",
-                            &[&title, &id],
-                        )
+                    cx.client()
+                        .await?
+                        .execute("update drafts set title = $1 where id = $2", &[&title, &id])
                         .await?;
                 } else {
-                    data.client
+                    cx.client()
+                        .await?
                         .execute(
                             "insert into drafts (path, title, author) values ($1, $2, $3)",
-                            &[&private, &title, &uid],
+                            &[&private, &title, &user.id],
                         )
                         .await?;
                 }
             }
             Ok(HttpResponse::Ok().finish())
-        } else {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
+        }
+        _ => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
             Ok(HttpResponse::Forbidden().body(body))
         }
-    } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
-        Ok(HttpResponse::Forbidden().body(body))
     }
 }
 
+fn forbidden_json() -> HttpResponse {
+    let body = json!({
+        "success": false,
+        "reason": "forbidden"
+    });
+    HttpResponse::Forbidden()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+}
+
 #[post("/api/setadmin")]
 pub async fn api_setadmin<'a>(
     admin_data: web::Form<SetAdminData>,
-    _req: HttpRequest,
+    req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
 ) -> Result<impl Responder> {
-    if let Some(username) = identity.identity() {
-        let admin = data.client.query_opt(
-            "select id from admins where uid = \
-             (select id as uid from users where username = $1)",
-            &[&username]
-        ).await?;
-        if admin.is_none() {
+    let client = data.client().await?;
+    match session::user(&req, &identity, &client).await? {
+        Some(user) if user.admin => {}
+        _ => return Ok(forbidden_json()),
+    }
+
+    if admin_data.value {
+        let existing = client
+            .query_opt("select id from admins where uid = $1", &[&admin_data.uid])
+            .await?;
+        if existing.is_some() {
             let body = json!({
                 "success": false,
-                "reason": "forbidden"
+                "reason": "bad request"
             });
             let body = serde_json::to_string(&body).unwrap();
 
-            return Ok(HttpResponse::Forbidden()
-               .header(http::header::CONTENT_TYPE, "application/json")
-               .body(body));
+            return Ok(HttpResponse::BadRequest()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body));
         }
+        client
+            .execute("insert into admins (uid) values ($1)", &[&admin_data.uid])
+            .await?;
+    } else {
+        client
+            .execute("delete from admins where uid = $1", &[&admin_data.uid])
+            .await?;
+    }
 
-        if admin_data.value {
-            let existing = data
-                .client
-                .query_opt("select id from admins where uid = $1", &[&admin_data.uid])
-                .await?;
-            if existing.is_some() {
-                let body = json!({
-                    "success": false,
-                    "reason": "bad request"
-                });
-                let body = serde_json::to_string(&body).unwrap();
-
-                return Ok(HttpResponse::BadRequest()
-                   .header(http::header::CONTENT_TYPE, "application/json")
-                   .body(body));
-            }
-            data
-                .client
-                .execute("insert into admins (uid) values ($1)", &[&admin_data.uid])
-                .await?;
-        } else {
-            data
-                .client
-                .execute("delete from admins where uid = $1", &[&admin_data.uid])
-                .await?;
-        }
+    let body = json!({
+        "success": true
+    });
+    let body = serde_json::to_string(&body).unwrap();
+
+    Ok(HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body))
+}
 
+// picks up edited language files and templates without a restart,
+// the same as sending the server a SIGHUP
+#[post("/api/reload")]
+pub async fn api_reload<'a>(
+    req: HttpRequest,
+    identity: Identity,
+    data: web::Data<ServerData<'a>>,
+) -> Result<impl Responder> {
+    // the connection isn't needed for the reload itself
+    let admin = session::user(&req, &identity, &*data.client().await?)
+        .await?
+        .map_or(false, |user| user.admin);
+    if !admin {
+        return Ok(forbidden_json());
+    }
+    // parse errors name files and quote them, only what `detail` allows is sent
+    let (mut response, body) = if let Err(err) = data.reload().await {
+        eprintln!("reload failed, keeping the previous version: {}", err);
         let body = json!({
-            "success": true
+            "success": false,
+            "reason": err.detail().unwrap_or_else(|| "reload failed".to_string())
         });
-        let body = serde_json::to_string(&body).unwrap();
-
-        Ok(HttpResponse::Ok()
-           .header(http::header::CONTENT_TYPE, "application/json")
-           .body(body))
+        (HttpResponse::InternalServerError(), body)
     } else {
         let body = json!({
-            "success": false,
-            "reason": "forbidden"
+            "success": true
         });
-        let body = serde_json::to_string(&body).unwrap();
-
-        Ok(HttpResponse::Forbidden()
-           .header(http::header::CONTENT_TYPE, "application/json")
-           .body(body))
-    }
+        (HttpResponse::Ok(), body)
+    };
+    Ok(response
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string()))
 }
 
 #[post("/api/setemployee")]
 pub async fn api_setemployee<'a>(
     employee_data: web::Form<SetEmployeeData>,
-    _req: HttpRequest,
+    req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
 ) -> Result<impl Responder> {
-    if let Some(username) = identity.identity() {
-        let admin = data.client.query_opt(
-            "select id from admins where uid = \
-             (select id as uid from users where username = $1)",
-            &[&username]
-        ).await?;
-        if admin.is_none() {
+    let client = data.client().await?;
+    match session::user(&req, &identity, &client).await? {
+        Some(user) if user.admin => {}
+        _ => return Ok(forbidden_json()),
+    }
+
+    if employee_data.value {
+        let existing = client
+            .query_opt(
+                "select id from employees where uid = $1",
+                &[&employee_data.uid],
+            )
+            .await?;
+        if existing.is_some() {
             let body = json!({
                 "success": false,
-                "reason": "forbidden"
+                "reason": "bad request"
             });
             let body = serde_json::to_string(&body).unwrap();
 
-            return Ok(HttpResponse::Forbidden()
-               .header(http::header::CONTENT_TYPE, "application/json")
-               .body(body));
-        }
-
-        if employee_data.value {
-            let existing = data
-                .client
-                .query_opt("select id from employees where uid = $1", &[&employee_data.uid])
-                .await?;
-            if existing.is_some() {
-                let body = json!({
-                    "success": false,
-                    "reason": "bad request"
-                });
-                let body = serde_json::to_string(&body).unwrap();
-
-                return Ok(HttpResponse::BadRequest()
-                   .header(http::header::CONTENT_TYPE, "application/json")
-                   .body(body));
-            }
-            data
-                .client
-                .execute("insert into employees (uid) values ($1)", &[&employee_data.uid])
-                .await?;
-        } else {
-            data
-                .client
-                .execute("delete from employees where uid = $1", &[&employee_data.uid])
-                .await?;
+            return Ok(HttpResponse::BadRequest()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body));
         }
-
-        let body = json!({
-            "success": true
-        });
-        let body = serde_json::to_string(&body).unwrap();
-
-        Ok(HttpResponse::Ok()
-           .header(http::header::CONTENT_TYPE, "application/json")
-           .body(body))
+        client
+            .execute(
+                "insert into employees (uid) values ($1)",
+                &[&employee_data.uid],
+            )
+            .await?;
     } else {
-        let body = json!({
-            "success": false,
-            "reason": "forbidden"
-        });
-        let body = serde_json::to_string(&body).unwrap();
-
-        Ok(HttpResponse::Forbidden()
-           .header(http::header::CONTENT_TYPE, "application/json")
-           .body(body))
+        client
+            .execute(
+                "delete from employees where uid = $1",
+                &[&employee_data.uid],
+            )
+            .await?;
     }
+
+    let body = json!({
+        "success": true
+    });
+    let body = serde_json::to_string(&body).unwrap();
+
+    Ok(HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body))
 }
 
 #[get("/api/draft")]
@@ -424,48 +338,35 @@ pub async fn api_draft<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if let Some(username) = identity.identity() {
-        let uid = data
-            .client
-            .query_one(
-                "select users.id as uid from users where username = $1",
-                &[&username],
-            )
-            .await?;
-        let uid = uid.get::<_, i32>("uid");
-        let article = data
-            .client
-            .query_one(
-                "select path, title from drafts where id = $1 and author = $2",
-                &[&draft_data.id, &uid],
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) => {
+            let article = cx
+                .client()
+                .await?
+                .query_opt(
+                    "select path, title from drafts where id = $1 and author = $2",
+                    &[&draft_data.id, &user.id],
                 )
-            .await?;
-        let content = fs::read_to_string(article.get::<_, &str>("path")).await?;
-        let title = article.get::<_, &str>("title");
-        let body = json!({
-            "content": content,
-            "title": title
-        });
-        let body = serde_json::to_string(&body)?;
-        Ok(HttpResponse::Ok()
-           .header(http::header::CONTENT_TYPE, "application/json")
-           .body(body))
-    } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
-        Ok(HttpResponse::Forbidden().body(body))
+                .await?
+                .ok_or_else(|| Error::ResourceNotFound(format!("draft {}", draft_data.id)))?;
+            let content = fs::read_to_string(article.get::<_, &str>("path")).await?;
+            let title = article.get::<_, &str>("title");
+            let body = json!({
+                "content": content,
+                "title": title
+            });
+            let body = serde_json::to_string(&body)?;
+            Ok(HttpResponse::Ok()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body))
+        }
+        None => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
+            Ok(HttpResponse::Forbidden().body(body))
+        }
     }
 }
 
@@ -475,93 +376,105 @@ pub async fn new<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if let Some(username) = identity.identity() {
-        let uid = data
-            .client
-            .query_one(
-                "select users.id as uid from users where username = $1",
-                &[&username],
-            )
-            .await?;
-        let uid = uid.get::<_, i32>("uid");
-        let user = data
-            .client
-            .query_opt(
-                "select employees.id from employees where employees.uid = $1",
-                &[&uid],
-            )
-            .await?;
-        if user.is_some() {
-            let auth_data = auth_data.into_inner();
-            let title = auth_data.title;
-            let article = auth_data.article;
-            let (mut public, mut private) = pathify(&title);
-            public.push_str(".md");
-            private.push_str(".md");
-            let existing = data
-                .client
-                .query(
-                    "select * from articles where title = $1 or path = $2",
-                    &[&title, &private],
-                )
-                .await?;
-            if !existing.is_empty() {
-                let mut body = fs::read_to_string("private/exists.html").await?;
-                template::search_replace_recursive(
-                    &identity,
-                    &data.client,
-                    &data.lang[&lang],
-                    &mut body,
-                    &[format!("article {}", title)],
-                )
-                .await?;
-                return Ok(HttpResponse::BadRequest().body(body));
-            }
-            fs::write(&private, article).await?;
-            data.client.execute("insert into articles (path, title, cdate, author) values ($1, $2, current_date, $3)", &[&public, &title, &uid]).await?;
-
-            let mut draft_path = draftify(&username, &title);
-            draft_path.push_str(".md");
-            data
-                .client
-                .execute(
-                    "delete from drafts where title = $1 and path = $2 and author = $3",
-                    &[&title, &draft_path, &uid],
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    let user = match cx.user().await? {
+        Some(user) if user.employee => user,
+        _ => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
+            return Ok(HttpResponse::Forbidden().body(body));
+        }
+    };
+    let auth_data = auth_data.into_inner();
+    let title = auth_data.title;
+    let article = auth_data.article;
+    let (mut public, mut private) = pathify(&data.config.public, &title);
+    public.push_str(".md");
+    private.push_str(".md");
+    let existing = cx
+        .client()
+        .await?
+        .query(
+            "select * from articles where title = $1 or path = $2",
+            &[&title, &private],
+        )
+        .await?;
+    if !existing.is_empty() {
+        let cx = cx.with_args(vec![format!("article {}", title)]);
+        let body = template::render(&cx, data.config.private.join("exists.html")).await?;
+        return Ok(HttpResponse::BadRequest().body(body));
+    }
+    let code = auth_data
+        .lang
+        .filter(|code| !code.is_empty())
+        .unwrap_or_else(|| data.config.default_lang.clone());
+    if !data.lang.current().contains_key(&code) {
+        return Err(Error::ResourceNotFound(format!("language {}", code)));
+    }
+    // translations always point at the first version, never at another translation
+    let original = match auth_data.original.filter(|id| !id.trim().is_empty()) {
+        Some(id) => {
+            let id: i32 = id
+                .trim()
+                .parse()
+                .map_err(|_| Error::InvalidParameter(format!("original {:?}", id)))?;
+            let root = cx
+                .client()
+                .await?
+                .query_opt(
+                    "select coalesce(original, id) as root from articles where id = $1",
+                    &[&id],
                 )
-                .await?;
-
-            Ok(HttpResponse::SeeOther()
-                .header("Location", format!("/{}", public))
-                .finish())
-        } else {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
+                .await?
+                .ok_or_else(|| Error::ResourceNotFound(format!("article {}", id)))?;
+            Some(root.get::<_, i32>("root"))
+        }
+        None => None,
+    };
+    if let Some(original) = original {
+        let translated = cx
+            .client()
+            .await?
+            .query_opt(
+                "select title from articles \
+                 where coalesce(original, id) = $1 and coalesce(lang, $2) = $3",
+                &[&original, &data.config.default_lang, &code],
             )
             .await?;
-            Ok(HttpResponse::Forbidden().body(body))
+        if let Some(translated) = translated {
+            let cx = cx.with_args(vec![format!(
+                "article {} ({})",
+                translated.get::<_, &str>("title"),
+                code
+            )]);
+            let body = template::render(&cx, data.config.private.join("exists.html")).await?;
+            return Ok(HttpResponse::BadRequest().body(body));
         }
-    } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
+    }
+    fs::write(&private, article).await?;
+    cx.client()
+        .await?
+        .execute(
+            "insert into articles (path, title, cdate, author, lang, original) \
+             values ($1, $2, current_date, $3, $4, $5)",
+            &[&public, &title, &user.id, &code, &original],
         )
         .await?;
-        Ok(HttpResponse::Forbidden().body(body))
-    }
+
+    let mut draft_path = draftify(&data.config.private, &user.username, &title);
+    draft_path.push_str(".md");
+    cx.client()
+        .await?
+        .execute(
+            "delete from drafts where title = $1 and path = $2 and author = $3",
+            &[&title, &draft_path, &user.id],
+        )
+        .await?;
+
+    Ok(HttpResponse::SeeOther()
+        .header("Location", format!("/{}", public))
+        .finish())
 }
 
 #[get("/account/editor.html")]
@@ -569,56 +482,21 @@ pub async fn editor<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if let Some(username) = identity.identity() {
-        let user = data
-            .client
-            .query_opt(
-                "select employees.id from employees where employees.uid = \
-             (select users.id as uid from users where username = $1)",
-                &[&username],
-            )
-            .await?;
-        if user.is_some() {
-            let mut body = fs::read_to_string("public/account/editor.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) if user.employee => {
+            let body =
+                template::render(&cx, data.config.public.join("account/editor.html")).await?;
             Ok(HttpResponse::Ok()
                 .header(http::header::CONTENT_TYPE, "text/html")
                 .body(body))
-        } else {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
+        }
+        _ => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
             Ok(HttpResponse::Forbidden().body(body))
         }
-    } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
-        )
-        .await?;
-        Ok(HttpResponse::Forbidden().body(body))
     }
 }
 
@@ -627,85 +505,49 @@ pub async fn draft<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    if let Some(username) = identity.identity() {
-        let uid = data
-            .client
-            .query_one(
-                "select users.id as uid from users where username = $1",
-                &[&username],
-            )
-            .await?;
-        let uid = uid.get::<_, i32>("uid");
-        let user = data
-            .client
-            .query_opt(
-                "select employees.id from employees where employees.uid = $1",
-                &[&uid],
-            )
-            .await?;
-        if user.is_some() {
-            let path = format!("private/{}/drafts/{}.md", username, info);
-            let existing = data
-                .client
-                .query_opt(
-                    "select title from drafts where author = $1 and path = $2",
-                    &[&uid, &path],
-                )
-                .await?;
-            if let Some(existing) = existing {
-                let mut body = fs::read_to_string("public/account/editor.html").await?;
-                let content = fs::read_to_string(path).await?;
-                let title = existing.get::<_, Option<&str>>("title");
-                let args = if let Some(title) = title {
-                    vec![content, title.to_string()]
-                } else {
-                    vec![content]
-                };
-                template::search_replace_recursive(
-                    &identity,
-                    &data.client,
-                    &data.lang[&lang],
-                    &mut body,
-                    &args,
-                )
-                .await?;
-                Ok(HttpResponse::Ok()
-                    .header(http::header::CONTENT_TYPE, "text/html")
-                    .body(body))
-            } else {
-                Ok(HttpResponse::SeeOther()
-                    .header("Location", "/account/editor.html".to_string())
-                    .finish())
-            }
-        } else {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
-            Ok(HttpResponse::Forbidden().body(body))
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    let user = match cx.user().await? {
+        Some(user) if user.employee => user,
+        _ => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
+            return Ok(HttpResponse::Forbidden().body(body));
         }
-    } else {
-        let mut body = fs::read_to_string("private/forbidden.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[],
+    };
+    let path = data
+        .config
+        .private
+        .join(&user.username)
+        .join(format!("drafts/{}.md", info))
+        .to_string_lossy()
+        .to_string();
+    let existing = cx
+        .client()
+        .await?
+        .query_opt(
+            "select title from drafts where author = $1 and path = $2",
+            &[&user.id, &path],
         )
         .await?;
-        Ok(HttpResponse::Forbidden().body(body))
+    if let Some(existing) = existing {
+        let content = fs::read_to_string(path).await?;
+        let title = existing.get::<_, Option<&str>>("title");
+        let args = if let Some(title) = title {
+            vec![content, title.to_string()]
+        } else {
+            vec![content]
+        };
+        let cx = cx.with_args(args);
+        let body = template::render(&cx, data.config.public.join("account/editor.html")).await?;
+        Ok(HttpResponse::Ok()
+            .header(http::header::CONTENT_TYPE, "text/html")
+            .body(body))
+    } else {
+        Ok(HttpResponse::SeeOther()
+            .header("Location", "/account/editor.html".to_string())
+            .finish())
     }
 }
 
@@ -713,10 +555,10 @@ pub async fn draft<'a>(
 pub async fn wasm<'a>(
     _req: HttpRequest,
     _identity: Identity,
-    _data: web::Data<ServerData<'a>>,
+    data: web::Data<ServerData<'a>>,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let path = format!("public/frontend/{}.wasm", info);
+    let path = data.config.public.join(format!("frontend/{}.wasm", info));
     let script = fs::read(path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "application/wasm")
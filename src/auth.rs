@@ -1,6 +1,4 @@
-use actix_http::HttpMessage;
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
-use tokio::fs;
 
 use actix_identity::Identity;
 use rand::prelude::*;
@@ -8,7 +6,9 @@ use serde::{Deserialize, Serialize};
 use tokio_postgres as psql;
 
 use crate::error::{Error, Result};
-use crate::template;
+use crate::i18n::Lang;
+use crate::session;
+use crate::template::{self, RenderContext};
 use crate::web::ServerData;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -50,13 +50,11 @@ pub async fn change_email<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    match identity.identity() {
-        Some(username) => {
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) => {
             let auth_data = auth_data.into_inner();
             let email = auth_data.email;
             if !email.contains('@') {
@@ -64,11 +62,12 @@ pub async fn change_email<'a>(
                     "e-mail is not an e-mail".to_string(),
                 ));
             }
-            let _userdata = query(&username, &auth_data.password, &data).await?;
-            data.client
+            let _userdata = query(&user.username, &auth_data.password, &cx.client().await?).await?;
+            cx.client()
+                .await?
                 .execute(
-                    "update users set email = $1 where username = $2",
-                    &[&email, &username],
+                    "update users set email = $1 where id = $2",
+                    &[&email, &user.id],
                 )
                 .await?;
             Ok(HttpResponse::SeeOther()
@@ -76,15 +75,7 @@ pub async fn change_email<'a>(
                 .finish())
         }
         None => {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
             Ok(HttpResponse::Forbidden().body(body))
         }
     }
@@ -96,15 +87,14 @@ pub async fn change_password<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    match identity.identity() {
-        Some(username) => {
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) => {
             let auth_data = auth_data.into_inner();
-            let _userdata = query(&username, &auth_data.old_password, &data).await?;
+            let _userdata =
+                query(&user.username, &auth_data.old_password, &cx.client().await?).await?;
             if auth_data.new_password.is_empty() {
                 return Err(Error::InvalidCreateUser("password is empty".to_string()));
             }
@@ -114,26 +104,21 @@ pub async fn change_password<'a>(
             let salt = salt();
             let pwhash =
                 argon2::hash_encoded(auth_data.new_password.as_bytes(), &salt, &data.argon)?;
-            data.client
+            cx.client()
+                .await?
                 .execute(
-                    "update users set pwhash = $1 where username = $2",
-                    &[&pwhash, &username],
+                    "update users set pwhash = $1 where id = $2",
+                    &[&pwhash, &user.id],
                 )
                 .await?;
+            // anyone who knew the old password may still be logged in elsewhere
+            session::revoke_others(&identity, &cx.client().await?, user.id).await?;
             Ok(HttpResponse::SeeOther()
                 .header("Location", "/account/me.html")
                 .finish())
         }
         None => {
-            let mut body = fs::read_to_string("private/forbidden.html").await?;
-            template::search_replace_recursive(
-                &identity,
-                &data.client,
-                &data.lang[&lang],
-                &mut body,
-                &[],
-            )
-            .await?;
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
             Ok(HttpResponse::Forbidden().body(body))
         }
     }
@@ -145,11 +130,8 @@ pub async fn create<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
     let auth_data = auth_data.into_inner();
     let firstname = if auth_data.firstname.is_empty() {
         None
@@ -170,19 +152,15 @@ pub async fn create<'a>(
     let username = auth_data.username;
     let email = auth_data.email;
     let existing = data
-        .client
+        .client()
+        .await?
         .query_opt("select * from users where username = $1", &[&username])
         .await?;
     if let Some(_existing) = existing {
-        let mut body = fs::read_to_string("private/exists.html").await?;
-        template::search_replace_recursive(
-            &identity,
-            &data.client,
-            &data.lang[&lang],
-            &mut body,
-            &[format!("user {}", username)],
-        )
-        .await?;
+        let cx = RenderContext::new(&req, &identity, &data, &lang)
+            .await?
+            .with_args(vec![format!("user {}", username)]);
+        let body = template::render(&cx, data.config.private.join("exists.html")).await?;
         return Ok(HttpResponse::BadRequest().body(body));
     }
     if !email.contains('@') {
@@ -197,13 +175,14 @@ pub async fn create<'a>(
     let pwhash = argon2::hash_encoded(auth_data.password.as_bytes(), &salt, &data.argon)?;
     match (firstname, lastname) {
         (Some(first), Some(last)) => {
-            data.client.execute("insert into users (firstname, lastname, username, email, pwhash) values ($1, $2, $3, $4, $5)", &[&first, &last, &username, &email, &pwhash]).await?;
+            data.client().await?.execute("insert into users (firstname, lastname, username, email, pwhash) values ($1, $2, $3, $4, $5)", &[&first, &last, &username, &email, &pwhash]).await?;
         }
         (Some(first), None) => {
-            data.client.execute("insert into users (firstname, username, email, pwhash) values ($1, $2, $3, $4)", &[&first, &username, &email, &pwhash]).await?;
+            data.client().await?.execute("insert into users (firstname, username, email, pwhash) values ($1, $2, $3, $4)", &[&first, &username, &email, &pwhash]).await?;
         }
         (None, Some(last)) => {
-            data.client
+            data.client()
+                .await?
                 .execute(
                     "insert into users (lastname, username, email, pwhash) values ($1, $2, $3, $4)",
                     &[&last, &username, &email, &pwhash],
@@ -211,7 +190,8 @@ pub async fn create<'a>(
                 .await?;
         }
         (None, None) => {
-            data.client
+            data.client()
+                .await?
                 .execute(
                     "insert into users (username, email, pwhash) values ($1, $2, $3)",
                     &[&username, &email, &pwhash],
@@ -225,14 +205,18 @@ pub async fn create<'a>(
 #[post("/auth/login.html")]
 pub async fn login<'a>(
     auth_data: web::Form<AuthData>,
-    _req: HttpRequest,
+    req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
 ) -> Result<impl Responder> {
     let auth_data = auth_data.into_inner();
-    let _userdata = query(&auth_data.username, &auth_data.password, &data).await?;
-    let username = auth_data.username;
-    identity.remember(username);
+    let userdata = query(
+        &auth_data.username,
+        &auth_data.password,
+        &*data.client().await?,
+    )
+    .await?;
+    session::create(&req, &identity, &data, userdata.get::<_, i32>("id")).await?;
     Ok(HttpResponse::SeeOther().header("Location", "/").finish())
 }
 
@@ -240,17 +224,41 @@ pub async fn login<'a>(
 pub async fn logout<'a>(
     _req: HttpRequest,
     identity: Identity,
-    _data: web::Data<ServerData<'a>>,
+    data: web::Data<ServerData<'a>>,
 ) -> Result<impl Responder> {
-    identity.forget();
+    session::revoke(&identity, &data).await?;
     Ok(HttpResponse::SeeOther().header("Location", "/").finish())
 }
 
-async fn query<'a>(username: &str, password: &str, data: &ServerData<'a>) -> Result<psql::Row> {
-    let userdata = data
-        .client
-        .query_one("select * from users where username = $1", &[&username])
-        .await?;
+#[post("/auth/logout-others.html")]
+pub async fn logout_others<'a>(
+    req: HttpRequest,
+    identity: Identity,
+    data: web::Data<ServerData<'a>>,
+    lang: Lang,
+) -> Result<impl Responder> {
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    match cx.user().await? {
+        Some(user) => {
+            session::revoke_others(&identity, &cx.client().await?, user.id).await?;
+            Ok(HttpResponse::SeeOther()
+                .header("Location", "/account/me.html")
+                .finish())
+        }
+        None => {
+            let body = template::render(&cx, data.config.private.join("forbidden.html")).await?;
+            Ok(HttpResponse::Forbidden().body(body))
+        }
+    }
+}
+
+async fn query(username: &str, password: &str, client: &psql::Client) -> Result<psql::Row> {
+    // an unknown user fails the same as a wrong password, so neither tells
+    // whether the username exists
+    let userdata = client
+        .query_opt("select * from users where username = $1", &[&username])
+        .await?
+        .ok_or(Error::AuthenticationFailed)?;
     let pwhash = userdata.get::<_, &str>("pwhash");
     let res = argon2::verify_encoded(pwhash, password.as_bytes())?;
     if res {
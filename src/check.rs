@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::l10n;
+use crate::path::PublicPath;
+use crate::template::{Reference, Registry, Template};
+
+fn html_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            html_files(&path, files)?;
+        } else if path.extension() == Some("html".as_ref()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// every html template in `public` and `private`, and every file they include,
+// compiled
+pub fn templates(config: &Config, registry: &Registry) -> Result<Vec<(PathBuf, Result<Template>)>> {
+    let mut files = Vec::new();
+    html_files(&config.public, &mut files)?;
+    html_files(&config.private, &mut files)?;
+    files.sort();
+    let mut seen: HashSet<PathBuf> = files.iter().cloned().collect();
+
+    let mut templates = Vec::new();
+    // includes are appended while iterating, so they are compiled as well
+    let mut idx = 0;
+    while idx < files.len() {
+        let path = files[idx].clone();
+        idx += 1;
+        let template = fs::read_to_string(&path)
+            .map_err(Error::from)
+            .and_then(|text| Template::compile(&path, &text, registry, &config.markdown));
+        for (_, reference) in template.iter().flat_map(|template| &template.refs) {
+            if let Reference::Include(include) = reference {
+                if let Ok(target) = PublicPath::with_root(&config.public) / &**include {
+                    if target.is_file() && seen.insert(target.to_path_buf()) {
+                        files.push(target.to_path_buf());
+                    }
+                }
+            }
+        }
+        templates.push((path, template));
+    }
+    Ok(templates)
+}
+
+// lints every html template in `public` and `private`, and every file they
+// include, printing one line per problem
+// returns the number of problems found
+pub fn check(config: &Config, registry: &Registry) -> Result<usize> {
+    let languages = l10n::languages(config)?;
+
+    let mut problems = 0;
+    let mut report = |problem: String| {
+        eprintln!("{}", problem);
+        problems += 1;
+    };
+    for (path, template) in templates(config, registry)? {
+        let template = match template {
+            Ok(template) => template,
+            Err(Error::Io(err)) => {
+                report(format!("{}: {}", path.display(), err));
+                continue;
+            }
+            Err(err) => {
+                report(err.to_string());
+                continue;
+            }
+        };
+        for (line, reference) in &template.refs {
+            match reference {
+                Reference::Include(include) => {
+                    match PublicPath::with_root(&config.public) / &**include {
+                        Ok(target) if target.is_file() => {}
+                        Ok(target) => report(format!(
+                            "{}:{}: included file {} doesn't exist",
+                            path.display(),
+                            line,
+                            target.display()
+                        )),
+                        Err(err) => report(format!("{}:{}: {}", path.display(), line, err)),
+                    }
+                }
+                Reference::L10n(key) => {
+                    for (_, lang) in languages.iter().filter(|(_, lang)| !lang.contains(key)) {
+                        report(format!(
+                            "{}:{}: l10n key {:?} is missing in {}",
+                            path.display(),
+                            line,
+                            key,
+                            lang.code()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    // a missing one would silently render as nothing
+    for key in l10n::BUILTIN {
+        for (_, lang) in languages.iter().filter(|(_, lang)| !lang.contains(key)) {
+            report(format!(
+                "l10n key {:?} used by the server is missing in {}",
+                key,
+                lang.code()
+            ));
+        }
+    }
+    for (_, lang) in &languages {
+        for (key, category) in lang.missing_forms() {
+            report(format!(
+                "l10n key {:?} in {} has no {:?} form",
+                key,
+                lang.code(),
+                category
+            ));
+        }
+    }
+    Ok(problems)
+}
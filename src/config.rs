@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+// used when neither `--config` nor `CIRCUS_CONFIG` is given
+pub const DEFAULT_PATH: &str = "circus.ron";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // plain HTTP, only redirects to HTTPS if `tls.redirect` is set
+    pub listen: Vec<String>,
+    pub tls: Option<TlsConfig>,
+    pub database: String,
+    // read instead of prompting, unless `--password-file` or `CIRCUS_DB_PASSWORD` is given
+    pub password_file: Option<PathBuf>,
+    pub database_tls: DatabaseTlsConfig,
+    pub pool: PoolConfig,
+    pub public: PathBuf,
+    pub private: PathBuf,
+    pub default_lang: String,
+    // languages to take missing keys from, in order, e.g. `{"pl": ["en"]}`
+    // `default_lang` always comes last
+    pub fallbacks: HashMap<String, Vec<String>>,
+    // where the site is reachable, e.g. `https://circus.example.org`, for
+    // canonical links, taken from the request if unset
+    pub url: Option<String>,
+    pub cookie: CookieConfig,
+    pub session: SessionConfig,
+    pub argon2: Argon2Config,
+    pub markdown: MarkdownConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseTlsConfig {
+    pub mode: SslMode,
+    // PEM encoded CA bundle trusted in addition to the system roots
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+    // per worker, the database sees up to one pool per cpu core
+    pub max_size: usize,
+    // in seconds
+    pub acquire_timeout: u64,
+    // idle connections are pinged before reuse after this many seconds
+    pub health_check: u64,
+}
+
+// same meaning as libpq's `sslmode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub listen: Vec<String>,
+    // PEM encoded certificate chain and private key
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub redirect: bool,
+    // max-age of the Strict-Transport-Security header in seconds, not sent if unset
+    #[serde(default)]
+    pub hsts: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookieConfig {
+    pub name: String,
+    pub path: String,
+    pub domain: Option<String>,
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+    // in seconds, session cookies if unset
+    pub max_age: Option<i64>,
+    // generated on first start if it doesn't exist
+    pub key: PathBuf,
+    // still accepted, but cookies are re-signed with `key`
+    pub previous_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    // in seconds
+    pub lifetime: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Argon2Config {
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub lanes: u32,
+    pub hash_length: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarkdownConfig {
+    // raw html in markdown is restricted to these tags, with these attributes
+    // `None` lets everyone writing articles embed anything, scripts included
+    pub allowed_html: Option<HashMap<String, Vec<String>>>,
+    // an `id` and a `#` link to it on every heading
+    pub heading_anchors: bool,
+    // documents with at least this many headings start with a table of contents
+    pub toc: Option<usize>,
+    // of links to other sites
+    pub external_rel: Option<String>,
+    pub lazy_images: bool,
+    // of fenced code blocks in the languages `markdown/highlight.rs` knows
+    pub highlight: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec!["127.0.0.1:8080".to_string()],
+            tls: None,
+            database: "host=localhost port=5432 dbname=circus user=circus".to_string(),
+            password_file: None,
+            database_tls: DatabaseTlsConfig::default(),
+            pool: PoolConfig::default(),
+            public: PathBuf::from("public"),
+            private: PathBuf::from("private"),
+            default_lang: "de".to_string(),
+            fallbacks: HashMap::new(),
+            url: None,
+            cookie: CookieConfig::default(),
+            session: SessionConfig::default(),
+            argon2: Argon2Config::default(),
+            markdown: MarkdownConfig::default(),
+        }
+    }
+}
+
+impl Default for DatabaseTlsConfig {
+    fn default() -> Self {
+        Self {
+            mode: SslMode::Prefer,
+            ca: None,
+        }
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 4,
+            acquire_timeout: 5,
+            health_check: 30,
+        }
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            name: "auth-cookie".to_string(),
+            path: "/".to_string(),
+            domain: None,
+            secure: false,
+            same_site: Some(SameSite::Lax),
+            max_age: None,
+            key: PathBuf::from("secret.key"),
+            previous_key: None,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        let config = argon2::Config::default();
+        Self {
+            mem_cost: config.mem_cost,
+            time_cost: config.time_cost,
+            lanes: config.lanes,
+            hash_length: config.hash_length,
+        }
+    }
+}
+
+// formatting, tables, links and images, but nothing that runs or loads on its own
+const ALLOWED_HTML: &[(&str, &[&str])] = &[
+    ("a", &["href", "title"]),
+    ("abbr", &["title"]),
+    ("b", &[]),
+    ("blockquote", &["cite"]),
+    ("br", &[]),
+    ("code", &[]),
+    ("dd", &[]),
+    ("del", &[]),
+    ("details", &["open"]),
+    ("div", &["class"]),
+    ("dl", &[]),
+    ("dt", &[]),
+    ("em", &[]),
+    ("figcaption", &[]),
+    ("figure", &[]),
+    ("h1", &["id"]),
+    ("h2", &["id"]),
+    ("h3", &["id"]),
+    ("h4", &["id"]),
+    ("h5", &["id"]),
+    ("h6", &["id"]),
+    ("hr", &[]),
+    ("i", &[]),
+    ("img", &["src", "alt", "title", "width", "height"]),
+    ("ins", &[]),
+    ("kbd", &[]),
+    ("li", &[]),
+    ("mark", &[]),
+    ("ol", &["start"]),
+    ("p", &[]),
+    ("pre", &[]),
+    ("s", &[]),
+    ("small", &[]),
+    ("span", &["class"]),
+    ("strong", &[]),
+    ("sub", &[]),
+    ("summary", &[]),
+    ("sup", &[]),
+    ("table", &[]),
+    ("tbody", &[]),
+    ("td", &["colspan", "rowspan"]),
+    ("th", &["colspan", "rowspan"]),
+    ("thead", &[]),
+    ("tr", &[]),
+    ("u", &[]),
+    ("ul", &[]),
+];
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        let allowed = ALLOWED_HTML
+            .iter()
+            .map(|(tag, attrs)| {
+                let attrs = attrs.iter().map(|attr| attr.to_string()).collect();
+                (tag.to_string(), attrs)
+            })
+            .collect();
+        Self {
+            allowed_html: Some(allowed),
+            heading_anchors: true,
+            toc: Some(3),
+            external_rel: Some("noopener noreferrer nofollow".to_string()),
+            lazy_images: true,
+            highlight: true,
+        }
+    }
+}
+
+impl Argon2Config {
+    pub fn to_argon2<'a>(&self) -> argon2::Config<'a> {
+        argon2::Config {
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            hash_length: self.hash_length,
+            ..argon2::Config::default()
+        }
+    }
+}
+
+fn psql_escape<S: AsRef<str>>(string: S) -> String {
+    string.as_ref().replace("\\", "\\\\").replace("'", "\\'")
+}
+
+impl Config {
+    // `path` comes from `--config`, falling back to `CIRCUS_CONFIG` and then
+    // to `circus.ron` in the current directory, if it exists
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let path = path
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("CIRCUS_CONFIG").map(PathBuf::from))
+            .or_else(|| Some(PathBuf::from(DEFAULT_PATH)).filter(|path| path.exists()));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        // cookies would never be sent back over the redirecting HTTP listeners anyway
+        if config.tls.as_ref().map(|tls| tls.redirect).unwrap_or(false) {
+            config.cookie.secure = true;
+        }
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Ok(ron::de::from_str(&text)?)
+    }
+
+    // environment variables take precedence over the configuration file
+    fn apply_env(&mut self) -> Result<()> {
+        if let Ok(listen) = env::var("CIRCUS_LISTEN") {
+            self.listen = listen
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(database) = env::var("CIRCUS_DATABASE") {
+            self.database = database;
+        }
+        if let Ok(mode) = env::var("CIRCUS_DATABASE_SSLMODE") {
+            match &mode[..] {
+                "disable" => self.database_tls.mode = SslMode::Disable,
+                "prefer" => self.database_tls.mode = SslMode::Prefer,
+                "require" => self.database_tls.mode = SslMode::Require,
+                "verify-ca" => self.database_tls.mode = SslMode::VerifyCa,
+                "verify-full" => self.database_tls.mode = SslMode::VerifyFull,
+                _ => return Err(Error::Env("CIRCUS_DATABASE_SSLMODE".to_string(), mode)),
+            }
+        }
+        if let Some(public) = env::var_os("CIRCUS_PUBLIC") {
+            self.public = PathBuf::from(public);
+        }
+        if let Some(private) = env::var_os("CIRCUS_PRIVATE") {
+            self.private = PathBuf::from(private);
+        }
+        if let Ok(lang) = env::var("CIRCUS_DEFAULT_LANG") {
+            self.default_lang = lang;
+        }
+        if let Ok(secure) = env::var("CIRCUS_COOKIE_SECURE") {
+            self.cookie.secure = secure == "1" || secure == "true";
+        }
+        Ok(())
+    }
+
+    // `code` followed by the languages to fall back to, `default_lang` last
+    pub fn chain(&self, code: &str) -> Vec<String> {
+        let mut chain = vec![code.to_string()];
+        let listed = self.fallbacks.get(code).into_iter().flatten();
+        for fallback in listed.chain(Some(&self.default_lang)) {
+            if !chain.contains(fallback) {
+                chain.push(fallback.clone());
+            }
+        }
+        chain
+    }
+
+    pub fn dsn(&self, password: &str) -> String {
+        // tokio-postgres only knows about disable, prefer and require,
+        // verification is up to the connector
+        let sslmode = match self.database_tls.mode {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => "require",
+        };
+        format!(
+            "{} sslmode={} password='{}'",
+            self.database,
+            sslmode,
+            psql_escape(password)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_end_in_the_default_without_repeating() {
+        let mut config = Config::default();
+        config.default_lang = "de".to_string();
+        config.fallbacks.insert(
+            "pl".to_string(),
+            vec!["en".to_string(), "de".to_string(), "pl".to_string()],
+        );
+        config
+            .fallbacks
+            .insert("en".to_string(), vec!["en".to_string()]);
+        assert_eq!(config.chain("pl"), vec!["pl", "en", "de"]);
+        assert_eq!(config.chain("en"), vec!["en", "de"]);
+        assert_eq!(config.chain("de"), vec!["de"]);
+        assert_eq!(config.chain("fr"), vec!["fr", "de"]);
+    }
+}
@@ -1,19 +1,27 @@
-use actix_web::ResponseError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
 use ron::de::Error as RonError;
 use serde_json::Error as JsonError;
+use std::error::Error as StdError;
 use std::fmt::{self, Display};
 use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
 use std::num::ParseIntError;
+use tokio_postgres::error::SqlState;
 use tokio_postgres::Error as DbError;
 
 #[derive(Debug)]
 pub enum Error {
     Ron(RonError),
+    // a language file that doesn't parse, by path
+    Language(String, RonError),
     Json(JsonError),
     Db(DbError),
     Io(IoError),
     Template(ParseIntError),
     Cmdline(String),
+    // an environment variable with a value it can't have, by name
+    Env(String, String),
     Useradd,
     CreateDb,
     ResourceNotFound(String),
@@ -23,19 +31,28 @@ pub enum Error {
     AuthorizationFailed,
     PasswordMismatch,
     InvalidCreateUser(String),
+    // a value sent with a request that isn't what it should be, e.g. not a number
+    InvalidParameter(String),
     InvalidPattern(String),
-    AsyncRecursion,
+    TemplateSyntax(String, usize, String),
+    TemplateDepth(String),
+    Migration(String),
+    InvalidSecret(String),
+    Tls(String),
+    PoolTimeout,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Ron(err) => Display::fmt(err, f),
+            Error::Language(path, err) => write!(f, "{}: {}", path, err),
             Error::Json(err) => Display::fmt(err, f),
             Error::Db(err) => Display::fmt(err, f),
             Error::Io(err) => Display::fmt(err, f),
             Error::Template(err) => write!(f, "template error: {}", err),
             Error::Cmdline(err) => write!(f, "command line error: {}", err),
+            Error::Env(name, value) => write!(f, "invalid value for {}: {:?}", name, value),
             Error::Useradd => write!(
                 f,
                 "creating the user `circus` failed (`useradd ... circus`)"
@@ -53,8 +70,14 @@ impl Display for Error {
             Error::InvalidCreateUser(desc) => {
                 write!(f, "invalid user creation parameter: {}", desc)
             }
+            Error::InvalidParameter(desc) => write!(f, "invalid parameter: {}", desc),
             Error::InvalidPattern(pat) => write!(f, "invalid pattern: {:?}", pat),
-            Error::AsyncRecursion => write!(f, "async recursion"),
+            Error::TemplateSyntax(path, line, desc) => write!(f, "{}:{}: {}", path, line, desc),
+            Error::TemplateDepth(path) => write!(f, "templates nested too deeply at {:?}", path),
+            Error::Migration(err) => write!(f, "migration error: {}", err),
+            Error::InvalidSecret(err) => write!(f, "invalid session key: {}", err),
+            Error::Tls(err) => write!(f, "tls error: {}", err),
+            Error::PoolTimeout => write!(f, "timed out waiting for a database connection"),
         }
     }
 }
@@ -95,6 +118,61 @@ impl From<JsonError> for Error {
     }
 }
 
-impl ResponseError for Error {}
+impl Error {
+    // what may be shown to the client, `None` for anything that could leak internals
+    pub fn detail(&self) -> Option<String> {
+        match self {
+            Error::ResourceNotFound(_)
+            | Error::IllegalResource(_)
+            | Error::AuthenticationFailed
+            | Error::AuthorizationFailed
+            | Error::PasswordMismatch
+            | Error::InvalidCreateUser(_)
+            | Error::InvalidParameter(_) => Some(self.to_string()),
+            _ => None,
+        }
+    }
+
+    // the database can't be reached, so nothing needing it should be tried
+    // while handling this error, it would only wait for a connection again
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Error::PoolTimeout => true,
+            // refused connections and broken ones are caused by an io error
+            Error::Db(err) => {
+                err.is_closed() || err.source().map_or(false, |cause| cause.is::<IoError>())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Io(err) if err.kind() == IoErrorKind::NotFound => StatusCode::NOT_FOUND,
+            Error::Db(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                StatusCode::CONFLICT
+            }
+            Error::ResourceNotFound(_) => StatusCode::NOT_FOUND,
+            Error::IllegalResource(_) => StatusCode::FORBIDDEN,
+            Error::AuthenticationFailed => StatusCode::UNAUTHORIZED,
+            Error::AuthorizationFailed => StatusCode::FORBIDDEN,
+            Error::PasswordMismatch | Error::InvalidCreateUser(_) | Error::InvalidParameter(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // replaced by a rendered page or a problem document in `error_page`,
+    // this is only what's left if that fails
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        HttpResponse::build(status)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(status.canonical_reason().unwrap_or("error"))
+    }
+}
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,112 @@
+use actix_http::body::{Body, ResponseBody};
+use actix_identity::Identity;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, HeaderValue, StatusCode};
+use actix_web::middleware::errhandlers::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{web, FromRequest, HttpRequest};
+use serde_json::json;
+
+use crate::error::{Error, Result};
+use crate::i18n::Lang;
+use crate::template::{self, RenderContext};
+use crate::web::ServerData;
+
+const STATUSES: &[StatusCode] = &[
+    StatusCode::BAD_REQUEST,
+    StatusCode::UNAUTHORIZED,
+    StatusCode::FORBIDDEN,
+    StatusCode::NOT_FOUND,
+    StatusCode::CONFLICT,
+    StatusCode::INTERNAL_SERVER_ERROR,
+];
+
+// templates in `private/` for every status an `Error` can map to
+fn page(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad-request.html",
+        StatusCode::UNAUTHORIZED => "unauthorized.html",
+        StatusCode::FORBIDDEN => "forbidden.html",
+        StatusCode::NOT_FOUND => "not-found.html",
+        StatusCode::CONFLICT => "conflict.html",
+        _ => "internal.html",
+    }
+}
+
+pub fn handlers<B: 'static>() -> ErrorHandlers<B> {
+    STATUSES
+        .iter()
+        .fold(ErrorHandlers::new(), |handlers, &status| {
+            handlers.handler(status, render)
+        })
+}
+
+async fn render_html(req: &HttpRequest, status: StatusCode) -> Result<String> {
+    let data = req
+        .app_data::<web::Data<ServerData<'static>>>()
+        .cloned()
+        .ok_or_else(|| Error::ResourceNotFound("server data".to_string()))?;
+    let identity = Identity::extract(req)
+        .await
+        .map_err(|_| Error::AuthorizationFailed)?;
+    let lang = Lang::negotiate(req, &data);
+    let cx = RenderContext::new(req, &identity, &data, &lang).await?;
+    template::render(&cx, data.config.private.join(page(status))).await
+}
+
+fn render_json(status: StatusCode, detail: Option<String>) -> String {
+    let title = status.canonical_reason().unwrap_or("Error");
+    let body = json!({
+        "type": "about:blank",
+        "title": title,
+        "status": status.as_u16(),
+        "detail": detail,
+        "success": false,
+        "reason": title.to_lowercase()
+    });
+    body.to_string()
+}
+
+fn replace_body<B>(
+    mut res: ServiceResponse<B>,
+    content_type: &'static str,
+    body: String,
+) -> ServiceResponse<B> {
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res.map_body(|_, _| ResponseBody::Other(Body::from(body)))
+}
+
+// only responses carrying an `Error` are replaced, so handlers that already
+// rendered a page of their own (e.g. `exists.html`) are left alone
+fn render<B: 'static>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let err = match res.response().error() {
+        Some(err) => err.as_error::<Error>(),
+        None => return Ok(ErrorHandlerResponse::Response(res)),
+    };
+    let detail = err.and_then(Error::detail);
+    let unavailable = err.map_or(false, Error::is_unavailable);
+    let status = res.status();
+    if res.request().path().starts_with("/api/") {
+        let body = render_json(status, detail);
+        return Ok(ErrorHandlerResponse::Response(replace_body(
+            res,
+            "application/problem+json",
+            body,
+        )));
+    }
+    // rendering a page may need a connection itself, so the plain text is
+    // sent right away instead of waiting for another `acquire_timeout`
+    if unavailable {
+        return Ok(ErrorHandlerResponse::Response(res));
+    }
+    Ok(ErrorHandlerResponse::Future(Box::pin(async move {
+        let rendered = render_html(res.request(), status).await;
+        match rendered {
+            Ok(body) => Ok(replace_body(res, "text/html; charset=utf-8", body)),
+            Err(err) => {
+                eprintln!("couldn't render error page for {}: {}", status, err);
+                Ok(res)
+            }
+        }
+    })))
+}
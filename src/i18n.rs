@@ -1,17 +1,26 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::ops::Index;
+use std::ops::{Deref, Index};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
 
 use std::borrow::Borrow;
 
 use serde::{Deserialize, Serialize};
 
+use actix_http::HttpMessage;
 use actix_identity::Identity;
 use actix_web::cookie::Cookie;
-use actix_web::{get, http, web, HttpRequest, HttpResponse, Responder};
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{get, http, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use futures::future::{ready, Ready};
+use tokio::fs;
 
-use crate::error::Result;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::pool::Pool;
 use crate::web::ServerData;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -19,6 +28,80 @@ pub struct Language {
     code: String,
     language: String,
     t9n: HashMap<String, String>,
+    // messages depending on a number, by plural category (`one`, `few`,
+    // `many`, `other`, ...), picked by the `count` placeholder
+    #[serde(default)]
+    plurals: HashMap<String, HashMap<String, String>>,
+}
+
+// the CLDR plural category of a whole number in the language `code`
+fn category(code: &str, count: i64) -> &'static str {
+    let count = count.abs();
+    match code.split('-').next().unwrap_or("") {
+        "pl" => {
+            if count == 1 {
+                "one"
+            } else if (2..=4).contains(&(count % 10)) && !(12..=14).contains(&(count % 100)) {
+                "few"
+            } else {
+                "many"
+            }
+        }
+        // de, en and most others
+        _ => {
+            if count == 1 {
+                "one"
+            } else {
+                "other"
+            }
+        }
+    }
+}
+
+// the categories `category` picks from for `code`
+fn categories(code: &str) -> &'static [&'static str] {
+    match code.split('-').next().unwrap_or("") {
+        "pl" => &["one", "few", "many"],
+        _ => &["one", "other"],
+    }
+}
+
+// replaces every `{name}` in `message` with the value of `name`, `{{` and
+// `}}` stand for the braces themselves, unknown placeholders are kept as they are
+fn interpolate(message: &str, args: &[(&str, String)]) -> String {
+    let mut output = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find(|ch| ch == '{' || ch == '}') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            output.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        if rest.starts_with('}') {
+            output.push('}');
+            rest = &rest[1..];
+            continue;
+        }
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            let (_, value) = args.iter().find(|(arg, _)| *arg == name)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                output.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
 }
 
 impl Language {
@@ -29,17 +112,248 @@ impl Language {
     pub fn language(&self) -> &str {
         &self.language
     }
+
+    // plain and plural messages alike, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.t9n
+            .keys()
+            .chain(self.plurals.keys())
+            .map(String::as_str)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.t9n.contains_key(key) || self.plurals.contains_key(key)
+    }
+
+    // takes over the keys this language is missing from `fallback`
+    pub fn fill(&mut self, fallback: &Language) {
+        for (key, value) in &fallback.t9n {
+            if !self.contains(key) {
+                self.t9n.insert(key.clone(), value.clone());
+            }
+        }
+        for (key, forms) in &fallback.plurals {
+            if !self.contains(key) {
+                self.plurals.insert(key.clone(), forms.clone());
+            }
+        }
+    }
+
+    // plural messages lacking a form this language needs, with that form
+    pub fn missing_forms(&self) -> Vec<(&str, &'static str)> {
+        let mut missing = Vec::new();
+        for (key, forms) in &self.plurals {
+            for category in categories(&self.code) {
+                if !forms.contains_key(*category) {
+                    missing.push((&key[..], *category));
+                }
+            }
+        }
+        missing.sort();
+        missing
+    }
+
+    // the message `key` with its placeholders filled in by `args`, a plural
+    // message takes the form for the number in `count`, `other` if it has none
+    pub fn format(&self, key: &str, args: &[(&str, String)]) -> String {
+        let message: &str = match self.plurals.get(key) {
+            Some(forms) => {
+                let category = args
+                    .iter()
+                    .find(|(name, _)| *name == "count")
+                    .and_then(|(_, count)| count.trim().parse().ok())
+                    .map_or("other", |count| category(&self.code, count));
+                match forms.get(category).or_else(|| forms.get("other")) {
+                    Some(form) => form,
+                    None => {
+                        eprintln!(
+                            "no {} form found for key {:?} in {}",
+                            category, key, self.code
+                        );
+                        ""
+                    }
+                }
+            }
+            None => &self[key],
+        };
+        interpolate(message, args)
+    }
 }
 
 impl<'a, S> Index<&'a S> for Language
 where
-    S: Eq + Hash + Debug,
+    S: Eq + Hash + Debug + ?Sized,
     String: Borrow<S>,
 {
     type Output = str;
 
+    // keys missing in the whole fallback chain render as nothing,
+    // `circus-backend check` lists them
     fn index(&self, key: &'a S) -> &Self::Output {
-        self.t9n.get(key).unwrap_or_else(|| panic!("no l10n found for key {:?}", key))
+        let plural = || self.plurals.get(key)?.get("other");
+        match self.t9n.get(key).or_else(plural) {
+            Some(value) => value,
+            None => {
+                eprintln!("no l10n found for key {:?} in {}", key, self.code);
+                ""
+            }
+        }
+    }
+}
+
+// every loaded language, by code
+pub type Catalog = HashMap<String, Language>;
+
+// the languages registered in the `l10n` table, shared by every worker
+// a reload swaps in all of them at once, requests keep the ones they started with
+pub struct Languages {
+    current: RwLock<Arc<Catalog>>,
+}
+
+// reads every registered language and fills in its missing keys along its
+// fallback chain
+pub async fn read(config: &Config, pool: &Pool) -> Result<Catalog> {
+    let client = pool.get().await?;
+    let mut langs = HashMap::new();
+    let l10n = client.query("select code, path from l10n", &[]).await?;
+    drop(client);
+    for row in l10n {
+        let key = row.get::<_, &str>("code").to_string();
+        let path = row.get::<_, &str>("path");
+        let text = fs::read_to_string(path).await?;
+        let lang: Language =
+            ron::de::from_str(&text).map_err(|err| Error::Language(path.to_string(), err))?;
+        langs.insert(key, lang);
+    }
+    if !langs.contains_key(&config.default_lang) {
+        return Err(Error::ResourceNotFound(format!(
+            "default language {}",
+            config.default_lang
+        )));
+    }
+    // filled in from the languages as loaded, so chains don't depend on the order
+    let loaded = langs.clone();
+    for (code, lang) in langs.iter_mut() {
+        for fallback in config.chain(code).iter().skip(1) {
+            if let Some(fallback) = loaded.get(fallback) {
+                lang.fill(fallback);
+            }
+        }
+    }
+    Ok(langs)
+}
+
+impl Languages {
+    pub fn new(catalog: Catalog) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(catalog)),
+        }
+    }
+
+    pub async fn load(config: &Config, pool: &Pool) -> Result<Self> {
+        Ok(Self::new(read(config, pool).await?))
+    }
+
+    pub fn current(&self) -> Arc<Catalog> {
+        Arc::clone(&self.current.read().expect("language lock poisoned"))
+    }
+
+    // languages already rendering a request keep theirs until it's done
+    // held by `web::reload` while it swaps in the templates as well
+    pub fn write(&self) -> RwLockWriteGuard<'_, Arc<Catalog>> {
+        self.current.write().expect("language lock poisoned")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LangQuery {
+    lang: Option<String>,
+}
+
+// the languages in an `Accept-Language` header, most preferred first
+fn accepted(header: &str) -> Vec<String> {
+    let mut langs: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+            let quality = parts
+                .find(|param| param.starts_with("q="))
+                .map(|param| param[2..].parse().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            Some((tag.to_lowercase(), quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // stable, so equally preferred languages keep their order
+    langs.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    langs.into_iter().map(|(tag, _)| tag).collect()
+}
+
+// the most preferred language of an `Accept-Language` header that's loaded,
+// `de-AT` matching `de` unless it's loaded itself
+fn best<F: Fn(&String) -> bool>(header: &str, loaded: F) -> Option<String> {
+    accepted(header).into_iter().find_map(|tag| {
+        let primary = tag.split('-').next().unwrap_or("").to_string();
+        Some(tag)
+            .filter(&loaded)
+            .or_else(|| Some(primary).filter(&loaded))
+    })
+}
+
+// the language of a request, always one that's loaded
+pub struct Lang {
+    catalog: Arc<Catalog>,
+    code: String,
+}
+
+impl Lang {
+    // `?lang=` first, so every language of a page has an address of its own,
+    // then the `lang` cookie, then `Accept-Language`, `de-AT` matching `de`,
+    // and the configured default last
+    pub fn negotiate(req: &HttpRequest, data: &ServerData<'_>) -> Self {
+        let catalog = data.lang.current();
+        let loaded = |lang: &String| catalog.contains_key(lang);
+        let query = web::Query::<LangQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().lang)
+            .filter(loaded);
+        let cookie = || {
+            req.cookie("lang")
+                .map(|cookie| cookie.value().to_string())
+                .filter(loaded)
+        };
+        let accept = || {
+            let value = req.headers().get(header::ACCEPT_LANGUAGE)?.to_str().ok()?;
+            best(value, loaded)
+        };
+        let code = query
+            .or_else(cookie)
+            .or_else(accept)
+            .unwrap_or_else(|| data.config.default_lang.clone());
+        Lang { catalog, code }
+    }
+}
+
+impl Deref for Lang {
+    type Target = Language;
+
+    fn deref(&self) -> &Language {
+        &self.catalog[&self.code]
+    }
+}
+
+impl FromRequest for Lang {
+    type Error = Error;
+    type Future = Ready<Result<Self>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let lang = req
+            .app_data::<web::Data<ServerData<'static>>>()
+            .map(|data| Lang::negotiate(req, data))
+            .ok_or_else(|| Error::ResourceNotFound("server data".to_string()));
+        ready(lang)
     }
 }
 
@@ -51,7 +365,7 @@ pub async fn lang<'a>(
     info: web::Path<String>,
 ) -> Result<impl Responder> {
     let lang = info.to_string();
-    if !data.lang.contains_key(&lang) {
+    if !data.lang.current().contains_key(&lang) {
         return Ok(HttpResponse::BadRequest()
             .finish());
     }
@@ -64,3 +378,116 @@ pub async fn lang<'a>(
         .header(http::header::LOCATION, "/")
         .finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_quality() {
+        assert_eq!(
+            accepted("de;q=0.5, en-GB, pl;q=0.8"),
+            vec!["en-gb", "pl", "de"]
+        );
+        // equally preferred ones keep their order
+        assert_eq!(accepted("fr, de;q=1.0, en"), vec!["fr", "de", "en"]);
+    }
+
+    #[test]
+    fn leaves_out_refused_and_wildcards() {
+        assert_eq!(accepted("en;q=0, de"), vec!["de"]);
+        assert_eq!(accepted("en;q=nope, de;q=0.1"), vec!["de"]);
+        assert_eq!(accepted("*;q=0.9, pl;q=0.2"), vec!["pl"]);
+        assert!(accepted("*").is_empty());
+        assert!(accepted("").is_empty());
+    }
+
+    #[test]
+    fn polish_has_three_forms() {
+        let forms: Vec<_> = [0, 1, 2, 4, 5, 11, 12, 14, 21, 22, 24, 25, 102, 112]
+            .iter()
+            .map(|&count| category("pl", count))
+            .collect();
+        assert_eq!(
+            forms,
+            vec![
+                "many", "one", "few", "few", "many", "many", "many", "many", "many", "few", "few",
+                "many", "few", "many"
+            ]
+        );
+        assert_eq!(category("pl-PL", -3), "few");
+    }
+
+    #[test]
+    fn others_have_two_forms() {
+        assert_eq!(category("en", 1), "one");
+        assert_eq!(category("en", 0), "other");
+        assert_eq!(category("de", 2), "other");
+    }
+
+    fn args(args: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        args.iter()
+            .map(|(name, value)| (*name, value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        let by = args(&[("author", "Max")]);
+        assert_eq!(interpolate("by {author}", &by), "by Max");
+        assert_eq!(interpolate("{author}, {author}", &by), "Max, Max");
+        // unknown or unterminated ones stay as they are
+        assert_eq!(
+            interpolate("{count} of {total}", &args(&[("count", "3")])),
+            "3 of {total}"
+        );
+        assert_eq!(interpolate("by {author", &by), "by {author");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let by = args(&[("author", "Max")]);
+        assert_eq!(
+            interpolate("{{author}} is {author}", &by),
+            "{author} is Max"
+        );
+        assert_eq!(interpolate("a }} b {{ c } d", &by), "a } b { c } d");
+        assert_eq!(interpolate("{{{author}}}", &by), "{Max}");
+    }
+
+    #[test]
+    fn count_picks_the_plural_form() {
+        let lang: Language = ron::de::from_str(
+            r#"(
+                code: "pl",
+                language: "Polski",
+                t9n: {},
+                plurals: {"files": {"one": "{count} plik", "few": "{count} pliki", "other": "plików: {count}"}},
+            )"#,
+        )
+        .expect("couldn't parse language");
+        assert_eq!(lang.format("files", &args(&[("count", "1")])), "1 plik");
+        assert_eq!(lang.format("files", &args(&[("count", "22")])), "22 pliki");
+        // no `many`, so `other`
+        assert_eq!(
+            lang.format("files", &args(&[("count", "12")])),
+            "plików: 12"
+        );
+        assert_eq!(lang.format("files", &[]), "plików: {count}");
+    }
+
+    fn pick(header: &str, loaded: &[&str]) -> Option<String> {
+        best(header, |lang| loaded.contains(&&lang[..]))
+    }
+
+    #[test]
+    fn falls_back_to_the_primary_subtag() {
+        assert_eq!(
+            pick("pl-PL, en;q=0.5", &["en", "pl"]).as_deref(),
+            Some("pl")
+        );
+        assert_eq!(pick("de-AT", &["de", "de-at"]).as_deref(), Some("de-at"));
+        assert_eq!(pick("fr-CH, en;q=0.1", &["en"]).as_deref(), Some("en"));
+        assert_eq!(pick("fr-CH, *", &["en"]), None);
+    }
+}
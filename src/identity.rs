@@ -0,0 +1,134 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use actix_http::HttpMessage;
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+use actix_web::cookie::SameSite as CookieSameSite;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use futures::future::{ready, Ready};
+use futures::FutureExt;
+use rand::prelude::*;
+
+use crate::config::{CookieConfig, SameSite};
+use crate::error::{Error, Result};
+
+pub const KEY_LEN: usize = 64;
+
+// the cookie crate refuses to derive signing keys from anything shorter
+const MIN_KEY_LEN: usize = 32;
+
+pub fn generate() -> Vec<u8> {
+    let mut key = vec![0; KEY_LEN];
+    thread_rng().fill_bytes(&mut key);
+    key
+}
+
+// writes the key readable only by the owner, refusing to overwrite an existing key
+pub fn write<P: AsRef<Path>>(path: P, key: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(key)?;
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let key = std::fs::read(&path)?;
+    if key.len() < MIN_KEY_LEN {
+        return Err(Error::InvalidSecret(format!(
+            "{} is {} bytes long, but at least {} bytes are required",
+            path.as_ref().display(),
+            key.len(),
+            MIN_KEY_LEN
+        )));
+    }
+    Ok(key)
+}
+
+pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    if path.as_ref().exists() {
+        load(path)
+    } else {
+        let key = generate();
+        write(&path, &key)?;
+        eprintln!("generated a new session key in {}", path.as_ref().display());
+        Ok(key)
+    }
+}
+
+fn cookie_policy(config: &CookieConfig, key: &[u8]) -> CookieIdentityPolicy {
+    let mut policy = CookieIdentityPolicy::new(key)
+        .name(&config.name)
+        .path(&config.path)
+        .secure(config.secure);
+    if let Some(domain) = &config.domain {
+        policy = policy.domain(domain);
+    }
+    if let Some(max_age) = config.max_age {
+        policy = policy.max_age(max_age);
+    }
+    if let Some(same_site) = config.same_site {
+        policy = policy.same_site(match same_site {
+            SameSite::Strict => CookieSameSite::Strict,
+            SameSite::Lax => CookieSameSite::Lax,
+            SameSite::None => CookieSameSite::None,
+        });
+    }
+    policy
+}
+
+// marks requests whose cookie was signed with the previous key,
+// so the response re-signs it with the current one
+struct Resign;
+
+pub struct RotatingIdentityPolicy {
+    current: CookieIdentityPolicy,
+    previous: Option<CookieIdentityPolicy>,
+}
+
+impl RotatingIdentityPolicy {
+    pub fn new(config: &CookieConfig, key: &[u8], previous: Option<&[u8]>) -> Self {
+        Self {
+            current: cookie_policy(config, key),
+            previous: previous.map(|key| cookie_policy(config, key)),
+        }
+    }
+}
+
+impl IdentityPolicy for RotatingIdentityPolicy {
+    type Future = Ready<std::result::Result<Option<String>, actix_web::Error>>;
+    type ResponseFuture = Ready<std::result::Result<(), actix_web::Error>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        let current = self
+            .current
+            .from_request(req)
+            .now_or_never()
+            .expect("`CookieIdentityPolicy` is always ready");
+        match current {
+            Ok(None) => {}
+            other => return ready(other),
+        }
+        if let Some(previous) = &self.previous {
+            if let Some(Ok(Some(identity))) = previous.from_request(req).now_or_never() {
+                req.extensions_mut().insert(Resign);
+                return ready(Ok(Some(identity)));
+            }
+        }
+        ready(Ok(None))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let resign = identity.is_some() && res.request().extensions().contains::<Resign>();
+        self.current.to_response(identity, changed || resign, res)
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tokio_postgres as psql;
+
+use crate::check;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::i18n::Language;
+use crate::template::{Reference, Registry};
+
+// keys the server looks up itself instead of a template, `check` makes sure
+// every language has them, keep in sync with `template/patterns.rs` and `template.rs`
+pub const BUILTIN: &[&str] = &[
+    "logout",
+    "logged_in_as",
+    "login",
+    "register",
+    "new_article",
+    "admin_panel",
+    "by_author",
+    "account_username",
+    "account_firstname",
+    "account_lastname",
+    "account_email",
+    "account_isemployee",
+    "account_isadmin",
+    "account_session_count",
+    "account_session_last_seen",
+    "account_session_ip",
+    "account_session_user_agent",
+    "account_session_current",
+    "editor_original_none",
+];
+
+// the code ends up in a file name
+fn is_valid(code: &str) -> bool {
+    !code.is_empty()
+        && code
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+}
+
+// where `new` puts the file of the language `code`
+pub fn path(config: &Config, code: &str) -> PathBuf {
+    config.public.join("l10n").join(format!("{}.ron", code))
+}
+
+fn read(path: &Path) -> Result<Language> {
+    let text = fs::read_to_string(path)?;
+    ron::de::from_str(&text).map_err(|err| Error::Language(path.display().to_string(), err))
+}
+
+// every language file, whether it's registered in the database or not
+pub fn languages(config: &Config) -> Result<Vec<(PathBuf, Language)>> {
+    let mut languages = Vec::new();
+    for entry in fs::read_dir(config.public.join("l10n"))? {
+        let path = entry?.path();
+        if path.extension() == Some("ron".as_ref()) {
+            let lang = read(&path)?;
+            languages.push((path, lang));
+        }
+    }
+    languages.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(languages)
+}
+
+fn find<'a>(languages: &'a [(PathBuf, Language)], code: &str) -> Result<&'a (PathBuf, Language)> {
+    languages
+        .iter()
+        .find(|(_, lang)| lang.code() == code)
+        .ok_or_else(|| Error::ResourceNotFound(format!("language {}", code)))
+}
+
+// prints the keys every other language is missing or has on top of `reference`
+// returns the number of differences
+pub fn compare(config: &Config, reference: &str) -> Result<usize> {
+    let languages = languages(config)?;
+    let (_, reference) = find(&languages, reference)?;
+    let expected: BTreeSet<&str> = reference.keys().collect();
+    let mut differences = 0;
+    for (_, lang) in &languages {
+        if lang.code() == reference.code() {
+            continue;
+        }
+        let keys: BTreeSet<&str> = lang.keys().collect();
+        for key in expected.difference(&keys) {
+            println!("{}: missing {:?}", lang.code(), key);
+            differences += 1;
+        }
+        for key in keys.difference(&expected) {
+            println!("{}: extra {:?}", lang.code(), key);
+            differences += 1;
+        }
+    }
+    Ok(differences)
+}
+
+fn mentions(haystack: &[u8], needle: &str) -> bool {
+    !needle.is_empty()
+        && haystack
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+}
+
+// prints the keys of any language that neither a template, nor the frontend,
+// nor the server itself refers to
+// returns how many there are
+pub fn unused(config: &Config, registry: &Registry) -> Result<usize> {
+    let mut used: HashSet<String> = BUILTIN.iter().map(|key| key.to_string()).collect();
+    // templates that don't compile are reported by `check`
+    for (_, template) in check::templates(config, registry)? {
+        for (_, reference) in template.iter().flat_map(|template| &template.refs) {
+            if let Reference::L10n(key) = reference {
+                used.insert(key.clone());
+            }
+        }
+    }
+    // the frontend asks `/api/t9n` for keys by name, any file mentioning one counts
+    let mut assets = Vec::new();
+    let frontend = config.public.join("frontend");
+    if frontend.is_dir() {
+        for entry in fs::read_dir(frontend)? {
+            let path = entry?.path();
+            if path.is_file() {
+                assets.push(fs::read(path)?);
+            }
+        }
+    }
+
+    let mut keys = BTreeSet::new();
+    for (_, lang) in languages(config)? {
+        keys.extend(lang.keys().map(String::from));
+    }
+    let mut unused = 0;
+    for key in keys {
+        if used.contains(&key) || assets.iter().any(|asset| mentions(asset, &key)) {
+            continue;
+        }
+        println!("{}", key);
+        unused += 1;
+    }
+    Ok(unused)
+}
+
+// writes a new language file for `code`, a copy of `reference` to translate
+pub fn scaffold(config: &Config, code: &str, name: &str, reference: &str) -> Result<PathBuf> {
+    if !is_valid(code) {
+        return Err(Error::Cmdline(format!("invalid language code: {:?}", code)));
+    }
+    let languages = languages(config)?;
+    if languages.iter().any(|(_, lang)| lang.code() == code) {
+        return Err(Error::IllegalResource(format!(
+            "language {} already exists",
+            code
+        )));
+    }
+    let target = path(config, code);
+    if target.exists() {
+        return Err(Error::IllegalResource(target.display().to_string()));
+    }
+    // copied as text, so the keys stay in the order and groups of the reference
+    let (source, reference) = find(&languages, reference)?;
+    let text = fs::read_to_string(source)?
+        .replacen(
+            &format!("code: {:?}", reference.code()),
+            &format!("code: {:?}", code),
+            1,
+        )
+        .replacen(
+            &format!("language: {:?}", reference.language()),
+            &format!("language: {:?}", name),
+            1,
+        );
+    let copy: Language = ron::de::from_str(&text)
+        .map_err(|err| Error::Language(source.display().to_string(), err))?;
+    if copy.code() != code || copy.language() != name {
+        return Err(Error::IllegalResource(format!(
+            "couldn't rename the copy of {}",
+            source.display()
+        )));
+    }
+    fs::write(&target, text)?;
+    Ok(target)
+}
+
+// the server loads `path` as the language `code` from its next start or reload on
+pub async fn register(client: &psql::Client, code: &str, path: &Path) -> Result<()> {
+    let lang = read(path)?;
+    if lang.code() != code {
+        return Err(Error::Cmdline(format!(
+            "{} is the language {:?}, not {:?}",
+            path.display(),
+            lang.code(),
+            code
+        )));
+    }
+    let path = path
+        .to_str()
+        .ok_or_else(|| Error::IllegalResource(path.display().to_string()))?;
+    client
+        .execute(
+            "insert into l10n (code, path) values ($1, $2) \
+             on conflict (code) do update set path = excluded.path",
+            &[&code, &path],
+        )
+        .await?;
+    Ok(())
+}
+
+// the language file itself is kept
+pub async fn unregister(client: &psql::Client, config: &Config, code: &str) -> Result<()> {
+    if code == config.default_lang {
+        return Err(Error::Cmdline(format!(
+            "{} is the default language, change `default_lang` first",
+            code
+        )));
+    }
+    let removed = client
+        .execute("delete from l10n where code = $1", &[&code])
+        .await?;
+    if removed == 0 {
+        return Err(Error::ResourceNotFound(format!("language {}", code)));
+    }
+    Ok(())
+}
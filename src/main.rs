@@ -1,23 +1,42 @@
 #![feature(async_closure)]
 
+use std::fs;
+use std::path::PathBuf;
 use std::process;
+use std::sync::Arc;
 
 use clap::{App as Clapp, Arg, ArgMatches, SubCommand};
-use tokio_postgres::NoTls;
 
-use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_web::{App, HttpServer};
-use arrayvec::ArrayString;
+use actix_identity::IdentityService;
+use actix_web::dev::Service;
+use actix_web::{http, App, HttpServer};
+use futures::future;
+use tokio::signal::unix::{signal, SignalKind};
 
+use crate::config::Config;
 use crate::error::{Error, Result};
+use crate::i18n::Languages;
+use crate::identity::RotatingIdentityPolicy;
+use crate::pool::Pool;
+use crate::template::{Registry, TemplateCache};
 
 pub mod account;
 pub mod auth;
+pub mod check;
+pub mod config;
 pub mod error;
+pub mod error_page;
 pub mod i18n;
+pub mod identity;
+pub mod l10n;
+pub mod markdown;
+pub mod migrate;
 pub mod path;
+pub mod pool;
+pub mod session;
 pub mod template;
 pub mod term;
+pub mod tls;
 pub mod web;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -25,17 +44,6 @@ const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const ABOUT: &str = "circus-backend is an open source webservice framework";
 const AFTER_HELP: &str = "This program was made possible by https://Zirkus-Internationale.de.";
 
-fn psql_escape<S: AsRef<str>>(string: S) -> String {
-    string.as_ref().replace("\\", "\\\\")
-}
-
-fn psql_config(password: &str) -> String {
-    format!(
-        "host=localhost port=5432 dbname=circus user=circus password='{}'",
-        psql_escape(password)
-    )
-}
-
 fn init_user<'a, 'b>(_matches: &'a ArgMatches<'b>) -> Result<()> {
     let mut child = process::Command::new("useradd")
         .arg("-m")
@@ -116,63 +124,145 @@ fn init_db<'a, 'b>(_matches: &'a ArgMatches<'b>) -> Result<()> {
     }
 }
 
-async fn init_tables<'a, 'b>(_matches: &'a ArgMatches<'b>) -> Result<()> {
-    let window = pancurses::initscr();
-    let password = term::prompt(&window, Some("Password: "), true);
-    pancurses::endwin();
-    let password = password.unwrap_or_else(String::new);
-    let data = web::ServerData::new(psql_config(&password), NoTls).await?;
-    data.client
-        .execute(
-            "create table if not exists articles
-                         (
-                             id serial primary key not null,
-                             path text not null,
-                             title text not null,
-                             cdate date not null,
-                             udate date,
-                             author text
-                         )",
-            &[],
-        )
-        .await?;
-    data.client
-        .execute(
-            "create table if not exists users
-                         (
-                             id serial primary key not null,
-                             username text not null,
-                             pwhash text not null,
-                             email text not null,
-                             firstname text,
-                             lastname text
-                         )",
-            &[],
-        )
-        .await?;
-    data.client
-        .execute(
-            "create table if not exists employees
-                         (
-                             id serial primary key not null,
-                             uid integer references users (id) not null
-                         )",
-            &[],
-        )
-        .await?;
-    data.client
-        .execute(
-            "create table if not exists admins
-                         (
-                             id serial primary key not null,
-                             uid integer references users (id) not null
-                         )",
-            &[],
-        )
-        .await?;
+fn password<'a, 'b>(matches: &'a ArgMatches<'b>, config: &Config) -> Result<String> {
+    let file = matches
+        .value_of("password-file")
+        .map(PathBuf::from)
+        .or_else(|| config.password_file.clone());
+    term::password(file)
+}
+
+async fn init_tables<'a, 'b>(matches: &'a ArgMatches<'b>) -> Result<()> {
+    let config = Config::load(matches.value_of("config"))?;
+    let password = password(matches, &config)?;
+    let (mut client, _handle) = web::connect(&config, &password).await?;
+    migrate::up(&mut client, None).await
+}
+
+async fn migrate<'a, 'b>(matches: &'a ArgMatches<'b>) -> Result<()> {
+    let config = Config::load(matches.value_of("config"))?;
+    let password = password(matches, &config)?;
+    let (mut client, _handle) = web::connect(&config, &password).await?;
+    match matches.subcommand() {
+        ("up", Some(matches)) => {
+            let target = matches.value_of("to").map(str::parse::<i32>).transpose()?;
+            migrate::up(&mut client, target).await
+        }
+        ("down", Some(matches)) => {
+            let steps = matches.value_of("steps").unwrap_or("1").parse()?;
+            migrate::down(&mut client, steps).await
+        }
+        ("status", Some(_matches)) => migrate::status(&client).await,
+        ("", _) => Err(Error::Cmdline("no migrate command passed".to_string())),
+        (x, _) => Err(Error::Cmdline(format!("unrecognized migrate command: {:?}", x))),
+    }
+}
+
+fn gen_secret<'a, 'b>(matches: &'a ArgMatches<'b>) -> Result<()> {
+    let config = Config::load(matches.value_of("config"))?;
+    let path = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config.cookie.key.clone());
+    if matches.is_present("rotate") && path.exists() {
+        let previous = config
+            .cookie
+            .previous_key
+            .clone()
+            .unwrap_or_else(|| path.with_extension("previous"));
+        fs::rename(&path, &previous)?;
+        println!(
+            "moved the current session key to {}, set `previous_key` to it in the \
+             configuration to keep existing sessions valid",
+            previous.display()
+        );
+    }
+    identity::write(&path, &identity::generate())?;
+    println!("wrote a new session key to {}", path.display());
+    Ok(())
+}
+
+fn check<'a, 'b>(matches: &'a ArgMatches<'b>, registry: &Registry) -> Result<()> {
+    let config = Config::load(matches.value_of("config"))?;
+    let problems = check::check(&config, registry)?;
+    if problems > 0 {
+        eprintln!("found {} problem(s) in the templates", problems);
+        process::exit(1);
+    }
+    println!("no problems found in the templates");
     Ok(())
 }
 
+async fn l10n<'a, 'b>(matches: &'a ArgMatches<'b>, registry: &Registry) -> Result<()> {
+    let config = Config::load(matches.value_of("config"))?;
+    match matches.subcommand() {
+        ("compare", Some(matches)) => {
+            let reference = matches
+                .value_of("reference")
+                .unwrap_or(&config.default_lang);
+            let differences = l10n::compare(&config, reference)?;
+            if differences > 0 {
+                eprintln!("found {} difference(s) to {}", differences, reference);
+                process::exit(1);
+            }
+            println!("every language has the same keys as {}", reference);
+            Ok(())
+        }
+        ("unused", Some(_matches)) => {
+            let unused = l10n::unused(&config, registry)?;
+            if unused > 0 {
+                eprintln!("found {} unused key(s)", unused);
+                process::exit(1);
+            }
+            println!("every key is in use");
+            Ok(())
+        }
+        ("new", Some(matches)) => {
+            let code = matches.value_of("code").unwrap_or("");
+            let name = matches.value_of("name").unwrap_or(code);
+            let reference = matches
+                .value_of("reference")
+                .unwrap_or(&config.default_lang);
+            let path = l10n::scaffold(&config, code, name, reference)?;
+            println!(
+                "wrote {}, translate it and run `l10n register {}`",
+                path.display(),
+                code
+            );
+            Ok(())
+        }
+        ("register", Some(matches)) => {
+            let code = matches.value_of("code").unwrap_or("");
+            let path = matches
+                .value_of("path")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| l10n::path(&config, code));
+            let password = password(matches, &config)?;
+            let (client, _handle) = web::connect(&config, &password).await?;
+            l10n::register(&client, code, &path).await?;
+            println!(
+                "registered {} as {}, reload or restart the server to use it",
+                path.display(),
+                code
+            );
+            Ok(())
+        }
+        ("unregister", Some(matches)) => {
+            let code = matches.value_of("code").unwrap_or("");
+            let password = password(matches, &config)?;
+            let (client, _handle) = web::connect(&config, &password).await?;
+            l10n::unregister(&client, &config, code).await?;
+            println!("unregistered {}, reload or restart the server", code);
+            Ok(())
+        }
+        ("", _) => Err(Error::Cmdline("no l10n command passed".to_string())),
+        (x, _) => Err(Error::Cmdline(format!(
+            "unrecognized l10n command: {:?}",
+            x
+        ))),
+    }
+}
+
 fn git_add<'a, 'b>(matches: &'a ArgMatches<'b>) -> Result<()> {
     let mut child = process::Command::new("git")
         .arg("add")
@@ -204,6 +294,26 @@ fn git_commit<'a, 'b>(matches: &'a ArgMatches<'b>) -> Result<()> {
     }
 }
 
+// `kill -HUP` reloads language files and templates, a failed reload is
+// reported and the previous version kept
+fn reload_on_hangup(
+    config: Config,
+    pool: Arc<Pool>,
+    languages: Arc<Languages>,
+    templates: Arc<TemplateCache>,
+) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    actix_rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match web::reload(&config, &pool, &languages, &templates).await {
+                Ok(()) => eprintln!("reloaded languages and templates"),
+                Err(err) => eprintln!("reload failed, keeping the previous version: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
 #[actix_rt::main]
 async fn main() -> Result<()> {
     let matches = Clapp::new("circus-backend")
@@ -211,12 +321,59 @@ async fn main() -> Result<()> {
         .author(AUTHORS)
         .about(ABOUT)
         .after_help(AFTER_HELP)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .global(true)
+                .value_name("FILE")
+                .help("reads the configuration from FILE (default: ./circus.ron)"),
+        )
+        .arg(
+            Arg::with_name("password-file")
+                .long("password-file")
+                .takes_value(true)
+                .global(true)
+                .value_name("FILE")
+                .help("reads the database password from FILE instead of prompting for it"),
+        )
         .subcommand(SubCommand::with_name("init-db").about(
             "initializes the circus database with the circus user (must \
                     be ran as `postgres`)",
         ))
+        .subcommand(SubCommand::with_name("init-tables").about(
+            "initializes the circus database tables (same as `migrate up`)",
+        ))
         .subcommand(
-            SubCommand::with_name("init-tables").about("initializes the circus database tables"),
+            SubCommand::with_name("migrate")
+                .about("manages the circus database schema")
+                .subcommand(
+                    SubCommand::with_name("up")
+                        .about("applies all pending migrations")
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .takes_value(true)
+                                .value_name("VERSION")
+                                .help("stops after applying the migration VERSION"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("down")
+                        .about("reverts the most recently applied migrations")
+                        .arg(
+                            Arg::with_name("steps")
+                                .long("steps")
+                                .takes_value(true)
+                                .value_name("N")
+                                .help("reverts N migrations (default: 1)"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("lists applied and pending migrations"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("init-user")
@@ -252,47 +409,178 @@ async fn main() -> Result<()> {
                         .value_name("MESSAGE"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("gen-secret")
+                .about("generates the key used to sign session cookies")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("writes the key to FILE instead of the configured `cookie.key`"),
+                )
+                .arg(
+                    Arg::with_name("rotate")
+                        .long("rotate")
+                        .help("moves an existing key to `cookie.previous_key` first"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("check").about(
+            "checks every template for syntax errors, missing includes and \
+                    missing l10n keys",
+        ))
+        .subcommand(
+            SubCommand::with_name("l10n")
+                .about("maintains the language files in `public/l10n`")
+                .subcommand(
+                    SubCommand::with_name("compare")
+                        .about("lists keys missing in or unknown to each language")
+                        .arg(
+                            Arg::with_name("reference")
+                                .long("reference")
+                                .takes_value(true)
+                                .value_name("CODE")
+                                .help("compares to the language CODE (default: `default_lang`)"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("unused").about(
+                    "lists keys neither a template, nor the frontend, nor the server \
+                        refers to",
+                ))
+                .subcommand(
+                    SubCommand::with_name("new")
+                        .about("writes a copy of the reference language to translate")
+                        .arg(
+                            Arg::with_name("code")
+                                .required(true)
+                                .value_name("CODE")
+                                .help("the language code, e.g. `fr`"),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .help("the name of the language in itself, e.g. `Français`"),
+                        )
+                        .arg(
+                            Arg::with_name("reference")
+                                .long("reference")
+                                .takes_value(true)
+                                .value_name("CODE")
+                                .help("copies the language CODE (default: `default_lang`)"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("register")
+                        .about("adds a language to the `l10n` table the server loads")
+                        .arg(Arg::with_name("code").required(true).value_name("CODE"))
+                        .arg(
+                            Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .value_name("FILE")
+                                .help("the language file (default: `public/l10n/CODE.ron`)"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("unregister")
+                        .about("removes a language from the `l10n` table, keeping its file")
+                        .arg(Arg::with_name("code").required(true).value_name("CODE")),
+                ),
+        )
         .subcommand(SubCommand::with_name("start").about(
-            "starts the circus webservice in the current directory (must \
-                    be ran as `circus`)",
+            "starts the circus webservice as configured by the configuration \
+                    file (must be ran as `circus`), SIGHUP reloads languages and templates",
         ))
         .get_matches();
 
+    // every pattern templates may use, site specific ones are registered on
+    // top of the built-in ones here, so `start`, `check` and `l10n unused`
+    // all know about them
+    let registry = Registry::default();
+
     match matches.subcommand() {
         ("init-db", Some(matches)) => init_db(matches),
         ("init-tables", Some(matches)) => init_tables(matches).await,
+        ("migrate", Some(matches)) => migrate(matches).await,
         ("init-user", Some(matches)) => init_user(matches),
         ("add", Some(matches)) => git_add(matches),
         ("commit", Some(matches)) => git_commit(matches),
-        ("start", Some(_matches)) => {
-            let window = pancurses::initscr();
-            let password = term::prompt(&window, Some("Password: "), true);
-            pancurses::endwin();
-            let password = password.unwrap_or_else(String::new);
-            let password = ArrayString::<[_; 255]>::from(&password).unwrap_or_else(|err| {
-                panic!(
-                    "The password is too long, length is {}, but maximum length is 255",
-                    err.element().len()
-                );
-            });
-            let data = move || web::ServerData::new(psql_config(&password), NoTls);
-            HttpServer::new(move || {
+        ("gen-secret", Some(matches)) => gen_secret(matches),
+        ("check", Some(matches)) => check(matches, &registry),
+        ("l10n", Some(matches)) => l10n(matches, &registry).await,
+        ("start", Some(matches)) => {
+            let config = Config::load(matches.value_of("config"))?;
+            let password = password(matches, &config)?;
+            let data = {
+                let config = config.clone();
+                // only for loading and reloading languages on this thread,
+                // every worker has a pool of its own
+                let pool = Arc::new(Pool::new(config.clone(), password.clone()));
+                let languages = Arc::new(Languages::load(&config, &pool).await?);
+                let templates = Arc::new(TemplateCache::new(registry, config.markdown.clone()));
+                reload_on_hangup(
+                    config.clone(),
+                    pool.clone(),
+                    languages.clone(),
+                    templates.clone(),
+                )?;
+                // called on every worker's thread, so connections are driven
+                // by the runtime of the worker using them
+                move || {
+                    web::ServerData::new(
+                        config.clone(),
+                        Arc::new(Pool::new(config.clone(), password.clone())),
+                        languages.clone(),
+                        templates.clone(),
+                    )
+                }
+            };
+            let cookie = config.cookie.clone();
+            let key = identity::load_or_generate(&cookie.key)?;
+            let previous_key = cookie
+                .previous_key
+                .as_ref()
+                .map(identity::load)
+                .transpose()?;
+            let hsts = config.tls.as_ref().and_then(|tls| tls.hsts);
+            let mut server = HttpServer::new(move || {
                 App::new()
-                    .data_factory(data)
-                    .wrap(IdentityService::new(
-                        CookieIdentityPolicy::new(&[0; 64])
-                            .name("auth-cookie")
-                            .secure(false),
-                    ))
+                    .data(data())
+                    // only the tls listeners send it, the plain ones may still
+                    // serve the site when `tls.redirect` is off
+                    .wrap_fn(move |req, srv| {
+                        let secure = req.app_config().secure();
+                        let res = srv.call(req);
+                        async move {
+                            let mut res = res.await?;
+                            if let (true, Some(max_age)) = (secure, hsts) {
+                                res.headers_mut().insert(
+                                    http::header::STRICT_TRANSPORT_SECURITY,
+                                    http::HeaderValue::from_str(&format!("max-age={}", max_age))
+                                        .expect("max-age is a valid header value"),
+                                );
+                            }
+                            Ok::<_, actix_web::Error>(res)
+                        }
+                    })
+                    .wrap(error_page::handlers())
+                    .wrap(IdentityService::new(RotatingIdentityPolicy::new(
+                        &cookie,
+                        &key,
+                        previous_key.as_deref(),
+                    )))
                     .service(auth::create)
                     .service(auth::login)
                     .service(auth::logout)
+                    .service(auth::logout_others)
                     .service(auth::change_email)
                     .service(auth::change_password)
                     .service(account::me)
                     .service(account::admin_panel)
                     .service(account::api_setadmin)
                     .service(account::api_setemployee)
+                    .service(account::api_reload)
                     .service(account::editor)
                     .service(account::draft)
                     .service(account::new)
@@ -309,11 +597,42 @@ async fn main() -> Result<()> {
                     .service(web::stylesheet)
                     .service(web::javascript)
                     .service(web::wasm)
-            })
-            .bind("127.0.0.1:8080")?
-            .run()
-            .await
-            .map_err(From::from)
+                    .default_service(actix_web::web::route().to(web::not_found))
+            });
+            let redirect = match &config.tls {
+                Some(tls) => {
+                    let rustls = tls::server_config(tls)?;
+                    for addr in &tls.listen {
+                        server = server.bind_rustls(addr, rustls.clone())?;
+                    }
+                    if tls.redirect {
+                        Some(tls::Redirect::new(tls))
+                    } else {
+                        None
+                    }
+                }
+                None => None,
+            };
+            match redirect {
+                Some(redirect) => {
+                    let mut redirect_server = HttpServer::new(move || {
+                        App::new()
+                            .data(redirect.clone())
+                            .default_service(actix_web::web::route().to(tls::redirect))
+                    });
+                    for addr in &config.listen {
+                        redirect_server = redirect_server.bind(addr)?;
+                    }
+                    future::try_join(server.run(), redirect_server.run()).await?;
+                    Ok(())
+                }
+                None => {
+                    for addr in &config.listen {
+                        server = server.bind(addr)?;
+                    }
+                    server.run().await.map_err(From::from)
+                }
+            }
         }
         ("", _) => Err(Error::Cmdline("no command passed".to_string())),
         (x, _) => Err(Error::Cmdline(format!("unrecognized command: {:?}", x))),
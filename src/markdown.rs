@@ -0,0 +1,474 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag};
+
+use crate::config::MarkdownConfig;
+
+mod highlight;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// links to other sites
+fn is_external(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//")
+}
+
+// relative urls and the schemes below, `javascript:` and the like are refused
+// an `&` before the first `/` could hide a `:` behind a character reference
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim();
+    let end = url.find(&['/', '?', '#'][..]).unwrap_or_else(|| url.len());
+    let head = &url[..end];
+    if head.contains('&') {
+        return false;
+    }
+    match head.find(':') {
+        Some(idx) => {
+            let scheme = head[..idx].to_lowercase();
+            scheme == "http" || scheme == "https" || scheme == "mailto"
+        }
+        None => true,
+    }
+}
+
+const URL_ATTRIBUTES: &[&str] = &["href", "src", "cite", "action", "formaction", "poster"];
+
+// the index of the `>` closing the tag at the start of `html`, skipping quoted values
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (idx, ch) in html.char_indices().skip(1) {
+        match (quote, ch) {
+            (None, '"') | (None, '\'') => quote = Some(ch),
+            (Some(open), ch) if open == ch => quote = None,
+            (None, '>') => return Some(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+// name and value of every attribute inside of a tag, with the tag name removed
+fn attributes(mut rest: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(|ch: char| ch.is_whitespace() || ch == '/');
+        if rest.is_empty() {
+            return attributes;
+        }
+        let end = rest
+            .find(|ch: char| ch.is_whitespace() || ch == '=' || ch == '/')
+            .unwrap_or_else(|| rest.len());
+        let name = rest[..end].to_lowercase();
+        rest = rest[end..].trim_start();
+        let mut value = String::new();
+        if rest.starts_with('=') {
+            rest = rest[1..].trim_start();
+            let quote = rest.chars().next().filter(|&ch| ch == '"' || ch == '\'');
+            let (val, next) = match quote {
+                Some(quote) => {
+                    let end = rest[1..]
+                        .find(quote)
+                        .map(|end| end + 1)
+                        .unwrap_or_else(|| rest.len());
+                    (&rest[1..end], &rest[(end + 1).min(rest.len())..])
+                }
+                None => {
+                    let end = rest.find(char::is_whitespace).unwrap_or_else(|| rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            value = val.to_string();
+            rest = next;
+        }
+        if !name.is_empty() {
+            attributes.push((name, value));
+        }
+    }
+}
+
+// keeps the tags and attributes `allowed` lists and drops everything else,
+// together with the contents of `script` and `style`
+// text in between is kept as it is, a stray `<` is escaped
+fn sanitize(html: &str, allowed: &HashMap<String, Vec<String>>) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map(|end| &rest[end + 3..]).unwrap_or("");
+            continue;
+        }
+        let is_tag = rest[1..].chars().next().map_or(false, |ch| {
+            ch.is_ascii_alphabetic() || ch == '/' || ch == '!'
+        });
+        let end = match tag_end(rest) {
+            Some(end) if is_tag => end,
+            _ => {
+                output.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/');
+        let name_end = tag
+            .find(|ch: char| ch.is_whitespace() || ch == '/')
+            .unwrap_or_else(|| tag.len());
+        let name = tag[..name_end].to_lowercase();
+        match allowed.get(&name) {
+            Some(_) if closing => write!(output, "</{}>", name).expect("couldn't write to string"),
+            Some(attrs) => {
+                output.push('<');
+                output.push_str(&name);
+                for (attr, value) in attributes(&tag[name_end..]) {
+                    if !attrs.contains(&attr) {
+                        continue;
+                    }
+                    if URL_ATTRIBUTES.contains(&&attr[..]) && !is_safe_url(&value) {
+                        continue;
+                    }
+                    write!(output, " {}=\"{}\"", attr, value.replace('"', "&quot;"))
+                        .expect("couldn't write to string");
+                }
+                output.push('>');
+            }
+            None if !closing && (name == "script" || name == "style") => {
+                let close = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(idx) => {
+                        let after = &rest[idx..];
+                        tag_end(after).map(|end| &after[end + 1..]).unwrap_or("")
+                    }
+                    None => "",
+                };
+            }
+            None => {}
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+// `Hello, World!` turns into `hello-world`
+fn slug(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if ch.is_alphanumeric() {
+            slug.extend(ch.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    slug
+}
+
+fn plain_text(events: &[Event]) -> String {
+    let mut text = String::new();
+    for event in events {
+        match event {
+            Event::Text(inner) | Event::Code(inner) => text.push_str(inner),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            _ => {}
+        }
+    }
+    text
+}
+
+// one entry per heading, indented by its level
+fn toc(headings: &[(u32, String, String)]) -> String {
+    let mut toc = String::from("<nav class=\"toc\">\n<ul>\n");
+    for (level, text, id) in headings {
+        write!(
+            toc,
+            "<li class=\"toc-h{}\"><a href=\"#{}\">{}</a></li>\n",
+            level,
+            escape(id),
+            escape(text)
+        )
+        .expect("couldn't write to string");
+    }
+    toc.push_str("</ul>\n</nav>\n");
+    toc
+}
+
+// sanitizes raw html and urls, highlights code, and adds `rel` to links and
+// `loading` to images
+fn filter<'a>(text: &'a str, config: &MarkdownConfig) -> Vec<Event<'a>> {
+    let mut events = Parser::new_ext(text, Options::all()).peekable();
+    let mut output = Vec::new();
+    while let Some(event) = events.next() {
+        match event {
+            // consecutive html is sanitized at once, block html comes one line at a time
+            Event::Html(html) => {
+                let mut html = html.to_string();
+                while let Some(Event::Html(next)) = events.peek() {
+                    html.push_str(next);
+                    events.next();
+                }
+                match &config.allowed_html {
+                    Some(allowed) => output.push(Event::Html(sanitize(&html, allowed).into())),
+                    None => output.push(Event::Html(html.into())),
+                }
+            }
+            Event::Start(Tag::Link(kind, url, title)) => {
+                let url = match &config.allowed_html {
+                    Some(_) if !is_safe_url(&url) => CowStr::from("#"),
+                    _ => url,
+                };
+                match &config.external_rel {
+                    Some(rel) if is_external(&url) => {
+                        let mut tag =
+                            format!("<a href=\"{}\" rel=\"{}\"", escape(&url), escape(rel));
+                        if !title.is_empty() {
+                            write!(tag, " title=\"{}\"", escape(&title))
+                                .expect("couldn't write to string");
+                        }
+                        tag.push('>');
+                        output.push(Event::Html(tag.into()));
+                    }
+                    _ => output.push(Event::Start(Tag::Link(kind, url, title))),
+                }
+            }
+            // `hl-*` classes, colored by `style.css`
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) if config.highlight => {
+                let mut code = String::new();
+                while let Some(event) = events.next() {
+                    match event {
+                        Event::End(Tag::CodeBlock(_)) => break,
+                        Event::Text(text) => code.push_str(&text),
+                        _ => {}
+                    }
+                }
+                let lang = info
+                    .split(|ch: char| ch == ',' || ch.is_whitespace())
+                    .next()
+                    .unwrap_or("");
+                match highlight::highlight(lang, &code) {
+                    Some(html) => output.push(Event::Html(
+                        format!(
+                            "<pre><code class=\"language-{}\">{}</code></pre>\n",
+                            escape(lang),
+                            html
+                        )
+                        .into(),
+                    )),
+                    None => {
+                        let kind = CodeBlockKind::Fenced(info);
+                        output.push(Event::Start(Tag::CodeBlock(kind.clone())));
+                        output.push(Event::Text(code.into()));
+                        output.push(Event::End(Tag::CodeBlock(kind)));
+                    }
+                }
+            }
+            Event::Start(Tag::Image(kind, url, title)) => {
+                let url = match &config.allowed_html {
+                    Some(_) if !is_safe_url(&url) => CowStr::from(""),
+                    _ => url,
+                };
+                if !config.lazy_images {
+                    output.push(Event::Start(Tag::Image(kind, url, title)));
+                    continue;
+                }
+                let mut alt = Vec::new();
+                while let Some(event) = events.next() {
+                    match event {
+                        Event::End(Tag::Image(..)) => break,
+                        event => alt.push(event),
+                    }
+                }
+                let mut tag = format!(
+                    "<img src=\"{}\" alt=\"{}\" loading=\"lazy\"",
+                    escape(&url),
+                    escape(&plain_text(&alt))
+                );
+                if !title.is_empty() {
+                    write!(tag, " title=\"{}\"", escape(&title)).expect("couldn't write to string");
+                }
+                tag.push_str(" />");
+                output.push(Event::Html(tag.into()));
+            }
+            event => output.push(event),
+        }
+    }
+    output
+}
+
+// renders untrusted markdown as html, following the policy in the configuration
+pub fn render(text: &str, config: &MarkdownConfig) -> String {
+    let mut events = filter(text, config).into_iter();
+    if !config.heading_anchors && config.toc.is_none() {
+        let mut html = String::new();
+        html::push_html(&mut html, events);
+        return html;
+    }
+
+    let mut output = Vec::new();
+    let mut headings = Vec::new();
+    let mut ids = HashSet::new();
+    while let Some(event) = events.next() {
+        match event {
+            Event::Start(Tag::Heading(level)) => {
+                let mut inner = Vec::new();
+                while let Some(event) = events.next() {
+                    match event {
+                        Event::End(Tag::Heading(_)) => break,
+                        event => inner.push(event),
+                    }
+                }
+                let text = plain_text(&inner);
+                let base = slug(&text);
+                let mut id = base.clone();
+                let mut no = 1;
+                while !ids.insert(id.clone()) {
+                    id = format!("{}-{}", base, no);
+                    no += 1;
+                }
+                output.push(Event::Html(
+                    format!("<h{} id=\"{}\">", level, escape(&id)).into(),
+                ));
+                if config.heading_anchors {
+                    output.push(Event::Html(
+                        format!("<a class=\"anchor\" href=\"#{}\">#</a> ", escape(&id)).into(),
+                    ));
+                }
+                output.extend(inner);
+                output.push(Event::Html(format!("</h{}>\n", level).into()));
+                headings.push((level, text, id));
+            }
+            event => output.push(event),
+        }
+    }
+
+    let mut html = String::new();
+    if let Some(min) = config.toc {
+        if headings.len() >= min {
+            html.push_str(&toc(&headings));
+        }
+    }
+    html::push_html(&mut html, output.into_iter());
+    html
+}
+
+// the first paragraph of a markdown document as plain text
+pub fn summary(text: &str) -> String {
+    let mut summary = String::new();
+    let mut inside = false;
+    for event in Parser::new_ext(text, Options::all()) {
+        match event {
+            Event::Start(Tag::Paragraph) => inside = true,
+            Event::End(Tag::Paragraph) => break,
+            Event::Text(text) | Event::Code(text) if inside => summary.push_str(&text),
+            Event::SoftBreak | Event::HardBreak if inside => summary.push(' '),
+            _ => {}
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> HashMap<String, Vec<String>> {
+        MarkdownConfig::default()
+            .allowed_html
+            .expect("html is restricted by default")
+    }
+
+    #[test]
+    fn refuses_scripting_urls() {
+        assert!(!is_safe_url("javascript:alert(1)"));
+        assert!(!is_safe_url(" JavaScript:alert(1)"));
+        assert!(!is_safe_url("data:text/html;base64,PHNjcmlwdD4="));
+        assert!(!is_safe_url("vbscript:msgbox(1)"));
+        assert!(!is_safe_url("java&#115;cript:alert(1)"));
+        assert!(is_safe_url("https://example.org/a:b"));
+        assert!(is_safe_url("mailto:circus@example.org"));
+        assert!(is_safe_url("/articles/foo.md"));
+        assert!(is_safe_url("articles/a:b"));
+        assert!(is_safe_url("#top"));
+    }
+
+    #[test]
+    fn drops_event_handlers_and_unsafe_urls() {
+        assert_eq!(
+            sanitize("<img src=\"a.png\" onerror=\"alert(1)\">", &allowed()),
+            "<img src=\"a.png\">"
+        );
+        assert_eq!(
+            sanitize(
+                "<a href='javascript:alert(1)' onclick=\"alert(1)\">hi</a>",
+                &allowed()
+            ),
+            "<a>hi</a>"
+        );
+        assert_eq!(
+            sanitize("<img src=data:image/svg+xml,x>", &allowed()),
+            "<img>"
+        );
+    }
+
+    #[test]
+    fn strips_scripts_and_unknown_tags() {
+        assert_eq!(
+            sanitize(
+                "a<script>alert(1)</script>b<SCRIPT >x</script >c",
+                &allowed()
+            ),
+            "abc"
+        );
+        assert_eq!(sanitize("<style>*{}</style>ok", &allowed()), "ok");
+        assert_eq!(
+            sanitize(
+                "<iframe src=\"https://example.org\">inner</iframe>",
+                &allowed()
+            ),
+            "inner"
+        );
+        assert_eq!(sanitize("1 < 2 <!-- <script> -->", &allowed()), "1 &lt; 2 ");
+    }
+
+    #[test]
+    fn renders_links_and_images_safely() {
+        let config = MarkdownConfig::default();
+        let html = render("[x](javascript:alert(1)) ![y](data:text/html,z)", &config);
+        assert!(!html.contains("javascript:"), "{}", html);
+        assert!(!html.contains("data:"), "{}", html);
+        let html = render(
+            "<iframe src=\"x\"></iframe><script>alert(1)</script>",
+            &config,
+        );
+        assert!(
+            !html.contains("<iframe") && !html.contains("<script"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn trusts_everything_without_a_policy() {
+        let config = MarkdownConfig {
+            allowed_html: None,
+            ..MarkdownConfig::default()
+        };
+        let html = render("<script>alert(1)</script>\n\n[x](javascript:y)", &config);
+        assert!(html.contains("<script>alert(1)</script>"), "{}", html);
+        assert!(html.contains("href=\"javascript:y\""), "{}", html);
+    }
+}
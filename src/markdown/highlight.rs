@@ -0,0 +1,355 @@
+use std::fmt::Write;
+
+use super::escape;
+
+// just enough of a language to tell comments, strings, numbers and keywords apart
+struct Syntax {
+    names: &'static [&'static str],
+    // separated by whitespace
+    keywords: &'static str,
+    literals: &'static str,
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    // besides alphanumerics and `_`, e.g. `-` in css
+    name_chars: &'static [char],
+    case_insensitive: bool,
+    // names starting with an uppercase letter are types
+    capitalized_types: bool,
+}
+
+const SYNTAXES: &[Syntax] = &[
+    Syntax {
+        names: &["rust", "rs"],
+        keywords:
+            "as async await break const continue crate dyn else enum extern fn for if impl in let \
+            loop match mod move mut pub ref return self Self static struct super trait type \
+            union unsafe use where while",
+        literals: "true false",
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: true,
+    },
+    Syntax {
+        names: &["c", "h", "cpp", "c++", "cc", "hpp"],
+        keywords:
+            "auto bool break case char class const continue default delete do double else enum \
+            extern float for goto if inline int long namespace new private protected public \
+            register return short signed sizeof static struct switch template this typedef \
+            typename union unsigned using virtual void volatile while",
+        literals: "true false NULL nullptr",
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: false,
+    },
+    Syntax {
+        names: &["java"],
+        keywords:
+            "abstract assert boolean break byte case catch char class continue default do double \
+            else enum extends final finally float for if implements import instanceof int \
+            interface long native new package private protected public return short static super \
+            switch synchronized this throw throws try var void volatile while",
+        literals: "true false null",
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: true,
+    },
+    Syntax {
+        names: &["javascript", "js", "typescript", "ts"],
+        keywords:
+            "async await break case catch class const continue debugger default delete do else \
+            enum export extends finally for function if implements import in instanceof \
+            interface let new of return static super switch this throw try type typeof var void \
+            while with yield",
+        literals: "true false null undefined NaN",
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+        name_chars: &['$'],
+        case_insensitive: false,
+        capitalized_types: true,
+    },
+    Syntax {
+        names: &["python", "py"],
+        keywords:
+            "and as assert async await break class continue def del elif else except finally for \
+            from global if import in is lambda nonlocal not or pass raise return try while with \
+            yield",
+        literals: "True False None",
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: true,
+    },
+    Syntax {
+        names: &["go", "golang"],
+        keywords:
+            "break case chan const continue default defer else fallthrough for func go goto if \
+            import interface map package range return select struct switch type var",
+        literals: "true false nil iota",
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: true,
+    },
+    Syntax {
+        names: &["sql", "psql", "postgresql"],
+        keywords:
+            "add all alter and as asc begin between by case commit create default delete desc \
+            distinct drop else end exists foreign from group having in index inner insert into \
+            is join key left like limit not offset on or order outer primary references \
+            returning right rollback select set table then union unique update values when where \
+            with",
+        literals: "true false null",
+        line_comments: &["--"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['\''],
+        name_chars: &[],
+        case_insensitive: true,
+        capitalized_types: false,
+    },
+    Syntax {
+        names: &["bash", "sh", "shell", "zsh"],
+        keywords:
+            "case do done elif else esac export fi for function if in local readonly return then \
+            until while",
+        literals: "true false",
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: false,
+    },
+    Syntax {
+        names: &["css"],
+        keywords: "!important",
+        literals: "auto inherit initial none unset",
+        line_comments: &[],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        name_chars: &['-', '!'],
+        case_insensitive: true,
+        capitalized_types: false,
+    },
+    Syntax {
+        names: &["json"],
+        keywords: "",
+        literals: "true false null",
+        line_comments: &[],
+        block_comment: None,
+        quotes: &['"'],
+        name_chars: &[],
+        case_insensitive: false,
+        capitalized_types: false,
+    },
+];
+
+fn span(html: &mut String, class: &str, text: &str) {
+    write!(html, "<span class=\"hl-{}\">{}</span>", class, escape(text))
+        .expect("couldn't write to string");
+}
+
+impl Syntax {
+    fn find(lang: &str) -> Option<&'static Syntax> {
+        let lang = lang.to_lowercase();
+        SYNTAXES
+            .iter()
+            .find(|syntax| syntax.names.contains(&&lang[..]))
+    }
+
+    fn is_name(&self, ch: char) -> bool {
+        ch.is_alphanumeric() || ch == '_' || self.name_chars.contains(&ch)
+    }
+
+    fn contains(&self, words: &str, word: &str) -> bool {
+        words.split_whitespace().any(|known| {
+            if self.case_insensitive {
+                known.eq_ignore_ascii_case(word)
+            } else {
+                known == word
+            }
+        })
+    }
+
+    // the class of a name, with what follows it to tell function calls apart
+    fn class(&self, word: &str, after: &str) -> Option<&'static str> {
+        if self.contains(self.keywords, word) {
+            Some("keyword")
+        } else if self.contains(self.literals, word) {
+            Some("literal")
+        } else if after.trim_start().starts_with('(') {
+            Some("function")
+        } else if self.capitalized_types && word.starts_with(char::is_uppercase) {
+            Some("type")
+        } else {
+            None
+        }
+    }
+}
+
+// the length of the string starting at the beginning of `text`, up to and
+// including the closing quote, or the rest of `text` if it's never closed
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (idx, ch) in text.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if ch == quote {
+            return idx + ch.len_utf8();
+        }
+    }
+    text.len()
+}
+
+// `code` as html, with `<span class="hl-...">` around everything that's
+// highlighted, `None` if `lang` isn't known
+pub fn highlight(lang: &str, code: &str) -> Option<String> {
+    let syntax = Syntax::find(lang)?;
+    let mut html = String::with_capacity(code.len() * 2);
+    let mut rest = code;
+    while let Some(ch) = rest.chars().next() {
+        let len = if syntax
+            .line_comments
+            .iter()
+            .any(|prefix| rest.starts_with(*prefix))
+        {
+            let len = rest.find('\n').unwrap_or_else(|| rest.len());
+            span(&mut html, "comment", &rest[..len]);
+            len
+        } else if let Some((open, close)) = syntax
+            .block_comment
+            .filter(|(open, _)| rest.starts_with(open))
+        {
+            let len = rest[open.len()..]
+                .find(close)
+                .map(|end| open.len() + end + close.len())
+                .unwrap_or_else(|| rest.len());
+            span(&mut html, "comment", &rest[..len]);
+            len
+        } else if syntax.quotes.contains(&ch) {
+            let len = string_len(rest, ch);
+            span(&mut html, "string", &rest[..len]);
+            len
+        } else if ch.is_ascii_digit() {
+            let len = rest
+                .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '.' || ch == '_'))
+                .unwrap_or_else(|| rest.len());
+            span(&mut html, "number", &rest[..len]);
+            len
+        } else if syntax.is_name(ch) {
+            let len = rest
+                .find(|ch: char| !syntax.is_name(ch))
+                .unwrap_or_else(|| rest.len());
+            let word = &rest[..len];
+            match syntax.class(word, &rest[len..]) {
+                Some(class) => span(&mut html, class, word),
+                None => html.push_str(&escape(word)),
+            }
+            len
+        } else {
+            html.push_str(&escape(&rest[..ch.len_utf8()]));
+            ch.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rust(code: &str) -> String {
+        highlight("rust", code).expect("rust is known")
+    }
+
+    #[test]
+    fn escapes_strings_comments_and_code() {
+        assert_eq!(
+            rust(r#""<b>&""#),
+            r#"<span class="hl-string">&quot;&lt;b&gt;&amp;&quot;</span>"#
+        );
+        assert_eq!(
+            rust(r#"// <b>&""#),
+            r#"<span class="hl-comment">// &lt;b&gt;&amp;&quot;</span>"#
+        );
+        assert_eq!(
+            rust("/* <b>& */"),
+            r#"<span class="hl-comment">/* &lt;b&gt;&amp; */</span>"#
+        );
+        assert_eq!(rust("a < b && c"), "a &lt; b &amp;&amp; c");
+        // `"` isn't a quote in sql
+        assert_eq!(
+            highlight("sql", r#""<x>""#).unwrap(),
+            "&quot;&lt;x&gt;&quot;"
+        );
+    }
+
+    #[test]
+    fn unterminated_strings_and_comments_reach_the_end() {
+        assert_eq!(
+            rust(r#"x = "a<b"#),
+            r#"x = <span class="hl-string">&quot;a&lt;b</span>"#
+        );
+        assert_eq!(rust(r#""a\"#), r#"<span class="hl-string">&quot;a\</span>"#);
+        assert_eq!(
+            rust("/* a <b"),
+            r#"<span class="hl-comment">/* a &lt;b</span>"#
+        );
+        // the opening `/*` doesn't close itself
+        assert_eq!(rust("/*/ x"), r#"<span class="hl-comment">/*/ x</span>"#);
+    }
+
+    #[test]
+    fn escaped_quotes_stay_in_strings() {
+        assert_eq!(
+            rust(r#""a\"b" c"#),
+            r#"<span class="hl-string">&quot;a\&quot;b&quot;</span> c"#
+        );
+        assert_eq!(
+            highlight("js", r#"'it\'s' x"#).unwrap(),
+            r#"<span class="hl-string">'it\'s'</span> x"#
+        );
+        assert_eq!(
+            rust(r#""a\\" b"#),
+            r#"<span class="hl-string">&quot;a\\&quot;</span> b"#
+        );
+    }
+
+    #[test]
+    fn keywords_match_whole_names_only() {
+        assert_eq!(rust("if x"), r#"<span class="hl-keyword">if</span> x"#);
+        assert_eq!(rust("letter iffy format_if"), "letter iffy format_if");
+        assert_eq!(
+            highlight("sql", "SELECT selection").unwrap(),
+            r#"<span class="hl-keyword">SELECT</span> selection"#
+        );
+        assert_eq!(
+            rust("f(1)"),
+            r#"<span class="hl-function">f</span>(<span class="hl-number">1</span>)"#
+        );
+    }
+
+    #[test]
+    fn unknown_languages_are_not_highlighted() {
+        assert_eq!(highlight("brainfuck", "+[-]"), None);
+        assert_eq!(highlight("", "x"), None);
+        assert!(highlight("Rust", "x").is_some());
+    }
+}
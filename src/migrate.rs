@@ -0,0 +1,227 @@
+use sha2::{Digest, Sha256};
+use tokio_postgres as psql;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+// every migration is embedded into the binary, sorted by version
+// never edit a migration that has been released, add a new one instead
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        up: include_str!("../migrations/0001_initial.up.sql"),
+        down: include_str!("../migrations/0001_initial.down.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "drafts",
+        up: include_str!("../migrations/0002_drafts.up.sql"),
+        down: include_str!("../migrations/0002_drafts.down.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "l10n",
+        up: include_str!("../migrations/0003_l10n.up.sql"),
+        down: include_str!("../migrations/0003_l10n.down.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "sessions",
+        up: include_str!("../migrations/0004_sessions.up.sql"),
+        down: include_str!("../migrations/0004_sessions.down.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "translations",
+        up: include_str!("../migrations/0005_translations.up.sql"),
+        down: include_str!("../migrations/0005_translations.down.sql"),
+    },
+];
+
+impl Migration {
+    // covers both directions, a changed `down` would revert something else
+    // than what `up` did
+    pub fn checksum(&self) -> String {
+        let digest = Sha256::new().chain(self.up).chain("\0").chain(self.down);
+        format!("{:x}", digest.result())
+    }
+
+    fn find(version: i32) -> Option<&'static Migration> {
+        MIGRATIONS.iter().find(|m| m.version == version)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Applied {
+    pub version: i32,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: String,
+}
+
+async fn init(client: &psql::Client) -> Result<()> {
+    client
+        .execute(
+            "create table if not exists schema_migrations
+                         (
+                             version integer primary key not null,
+                             name text not null,
+                             checksum text not null,
+                             applied_at timestamp not null default now()
+                         )",
+            &[],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn applied(client: &psql::Client) -> Result<Vec<Applied>> {
+    init(client).await?;
+    let rows = client
+        .query(
+            "select version, name, checksum, \
+             to_char(applied_at, 'yyyy-mm-dd hh24:mi:ss') as applied_at \
+             from schema_migrations order by version",
+            &[],
+        )
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Applied {
+            version: row.get("version"),
+            name: row.get::<_, &str>("name").to_string(),
+            checksum: row.get::<_, &str>("checksum").to_string(),
+            applied_at: row.get::<_, &str>("applied_at").to_string(),
+        })
+        .collect())
+}
+
+// refuses to touch a database whose history doesn't match the embedded migrations
+fn verify(applied: &[Applied]) -> Result<()> {
+    for row in applied {
+        match Migration::find(row.version) {
+            Some(migration) => {
+                if migration.checksum() != row.checksum {
+                    return Err(Error::Migration(format!(
+                        "checksum mismatch for migration {:04}_{}",
+                        row.version, row.name
+                    )));
+                }
+            }
+            None => {
+                return Err(Error::Migration(format!(
+                    "database has migration {:04}_{}, which is unknown to this binary",
+                    row.version, row.name
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+// an arbitrary key, only one `migrate up` or `migrate down` runs at a time
+// so two of them never apply the same migration
+const LOCK: i64 = 0x6369_7263_7573;
+
+async fn lock(client: &psql::Client) -> Result<()> {
+    client
+        .execute("select pg_advisory_lock($1)", &[&LOCK])
+        .await?;
+    Ok(())
+}
+
+async fn unlock(client: &psql::Client) -> Result<()> {
+    client
+        .execute("select pg_advisory_unlock($1)", &[&LOCK])
+        .await?;
+    Ok(())
+}
+
+pub async fn up(client: &mut psql::Client, target: Option<i32>) -> Result<()> {
+    lock(client).await?;
+    let result = apply(client, target).await;
+    unlock(client).await?;
+    result
+}
+
+pub async fn down(client: &mut psql::Client, steps: usize) -> Result<()> {
+    lock(client).await?;
+    let result = revert(client, steps).await;
+    unlock(client).await?;
+    result
+}
+
+// the applied migrations are only read once the lock is held
+async fn apply(client: &mut psql::Client, target: Option<i32>) -> Result<()> {
+    let applied = applied(client).await?;
+    verify(&applied)?;
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|row| row.version == m.version))
+        .filter(|m| target.map(|target| m.version <= target).unwrap_or(true));
+    for migration in pending {
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.up).await?;
+        tx.execute(
+            "insert into schema_migrations (version, name, checksum) values ($1, $2, $3)",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        )
+        .await?;
+        tx.commit().await?;
+        println!("applied {:04}_{}", migration.version, migration.name);
+    }
+    Ok(())
+}
+
+async fn revert(client: &mut psql::Client, steps: usize) -> Result<()> {
+    let applied = applied(client).await?;
+    verify(&applied)?;
+    for row in applied.iter().rev().take(steps) {
+        let migration =
+            Migration::find(row.version).expect("`verify()` didn't reject an unknown migration");
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.down).await?;
+        tx.execute(
+            "delete from schema_migrations where version = $1",
+            &[&migration.version],
+        )
+        .await?;
+        tx.commit().await?;
+        println!("reverted {:04}_{}", migration.version, migration.name);
+    }
+    Ok(())
+}
+
+pub async fn status(client: &psql::Client) -> Result<()> {
+    let applied = applied(client).await?;
+    for migration in MIGRATIONS {
+        match applied.iter().find(|row| row.version == migration.version) {
+            Some(row) if row.checksum != migration.checksum() => println!(
+                "[!] {:04}_{} (applied {}, checksum mismatch)",
+                migration.version, migration.name, row.applied_at
+            ),
+            Some(row) => println!(
+                "[x] {:04}_{} (applied {})",
+                migration.version, migration.name, row.applied_at
+            ),
+            None => println!("[ ] {:04}_{}", migration.version, migration.name),
+        }
+    }
+    for row in &applied {
+        if Migration::find(row.version).is_none() {
+            println!(
+                "[?] {:04}_{} (unknown to this binary)",
+                row.version, row.name
+            );
+        }
+    }
+    Ok(())
+}
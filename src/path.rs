@@ -11,25 +11,21 @@ pub struct PublicPath<'a>(Cow<'a, Path>);
 
 impl<'a> PublicPath<'a> {
     pub fn new() -> Self {
-        Self(Cow::Borrowed("public".as_ref()))
+        Self::with_root("public")
     }
 
-    fn try_from_path(path: Cow<'a, Path>) -> Result<Self, Error> {
-        if Self::check(&path) {
-            Ok(Self(path))
-        } else {
-            Err(Error::IllegalResource(path.to_string_lossy().to_string()))
-        }
+    pub fn with_root<P: AsRef<Path> + ?Sized>(root: &'a P) -> Self {
+        Self(Cow::Borrowed(root.as_ref()))
     }
 
-    // checks that a path doesn't point to anything outside of public
-    // recursing into public/ increases `level`
+    // checks that a relative path doesn't point to anything outside of its root
+    // recursing into a directory increases `level`
     // going outside (../) decreases `level`
-    // as soon as we step out of public/ (`level` < 1`), return false
+    // as soon as we step out of the root (`level` < 0`), return false
     // prefix (C:) and root (/) also return false
     // in every other case, return true
     fn check<P: AsRef<Path>>(path: P) -> bool {
-        let mut level = 1;
+        let mut level = 0;
         for c in path.as_ref().components() {
             match c {
                 Component::Prefix(_) => return false,
@@ -37,7 +33,7 @@ impl<'a> PublicPath<'a> {
                 Component::CurDir => {}
                 Component::ParentDir => {
                     level -= 1;
-                    if level < 1 {
+                    if level < 0 {
                         return false;
                     }
                 }
@@ -87,7 +83,13 @@ where
     type Output = Result<Self, Error>;
 
     fn div(self, other: T) -> Self::Output {
-        Self::try_from_path(Cow::Owned(self.0.join(other.as_ref())))
+        if Self::check(&other) {
+            Ok(Self(Cow::Owned(self.0.join(other.as_ref()))))
+        } else {
+            Err(Error::IllegalResource(
+                other.as_ref().to_string_lossy().to_string(),
+            ))
+        }
     }
 }
 
@@ -0,0 +1,123 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_postgres as psql;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::web;
+
+struct Connection {
+    client: psql::Client,
+    _handle: JoinHandle<()>,
+    idle_since: Instant,
+}
+
+// a bounded set of database connections, every worker has a pool of its own
+// a connection is driven by the runtime it was opened on, so it's only ever
+// handed out on that worker's thread
+// connections that died (e.g. because the database restarted) are dropped
+// when they are handed out or returned, and replaced by new ones on demand
+pub struct Pool {
+    config: Config,
+    password: String,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    permits: Arc<Semaphore>,
+}
+
+// doesn't borrow the pool, so it can be kept in a `RenderContext` once it's
+// taken without tying the context to the lifetime of the pool
+pub struct PooledClient {
+    idle: Arc<Mutex<Vec<Connection>>>,
+    permits: Arc<Semaphore>,
+    conn: Option<Connection>,
+}
+
+impl Pool {
+    pub fn new(config: Config, password: String) -> Self {
+        let permits = Semaphore::new(config.pool.max_size);
+        Self {
+            config,
+            password,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            permits: Arc::new(permits),
+        }
+    }
+
+    pub async fn get(&self) -> Result<PooledClient> {
+        let timeout = Duration::from_secs(self.config.pool.acquire_timeout);
+        time::timeout(timeout, self.acquire())
+            .await
+            .map_err(|_| Error::PoolTimeout)?
+    }
+
+    async fn acquire(&self) -> Result<PooledClient> {
+        let permit = self.permits.acquire().await;
+        let conn = loop {
+            let conn = self.idle.lock().expect("pool mutex poisoned").pop();
+            match conn {
+                Some(conn) => {
+                    if self.healthy(&conn).await {
+                        break conn;
+                    }
+                }
+                None => {
+                    let (client, handle) = web::connect(&self.config, &self.password).await?;
+                    break Connection {
+                        client,
+                        _handle: handle,
+                        idle_since: Instant::now(),
+                    };
+                }
+            }
+        };
+        // given back when the client is dropped
+        permit.forget();
+        Ok(PooledClient {
+            idle: Arc::clone(&self.idle),
+            permits: Arc::clone(&self.permits),
+            conn: Some(conn),
+        })
+    }
+
+    // only pings connections that sat idle for longer than `pool.health_check`
+    async fn healthy(&self, conn: &Connection) -> bool {
+        if conn.client.is_closed() {
+            return false;
+        }
+        if conn.idle_since.elapsed() < Duration::from_secs(self.config.pool.health_check) {
+            return true;
+        }
+        conn.client.simple_query("select 1").await.is_ok()
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = psql::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self
+            .conn
+            .as_ref()
+            .expect("connection is only taken when dropped")
+            .client
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(mut conn) = self.conn.take() {
+            if !conn.client.is_closed() {
+                conn.idle_since = Instant::now();
+                if let Ok(mut idle) = self.idle.lock() {
+                    idle.push(conn);
+                }
+            }
+        }
+        self.permits.add_permits(1);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+use actix_identity::Identity;
+use actix_web::{http, HttpRequest};
+use rand::prelude::*;
+use tokio_postgres as psql;
+
+use crate::error::Result;
+use crate::template::User;
+use crate::web::ServerData;
+
+// the identity cookie only carries this random id, everything else stays in `sessions`
+fn session_id() -> String {
+    let bytes: [u8; 32] = random();
+    let mut id = String::with_capacity(bytes.len() * 2);
+    for byte in bytes.iter() {
+        write!(id, "{:02x}", byte).expect("couldn't write to string");
+    }
+    id
+}
+
+// the address of the peer itself, `X-Forwarded-For` and `Forwarded` are up to
+// the client to send, so they'd let anyone choose the address that's shown
+fn client_info(req: &HttpRequest) -> (Option<String>, Option<String>) {
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    let user_agent = req
+        .headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(String::from);
+    (ip, user_agent)
+}
+
+pub async fn create<'a>(
+    req: &HttpRequest,
+    identity: &Identity,
+    data: &ServerData<'a>,
+    uid: i32,
+) -> Result<()> {
+    let id = session_id();
+    let (ip, user_agent) = client_info(req);
+    let lifetime = data.config.session.lifetime as f64;
+    let client = data.client().await?;
+    // only the sessions of this user, so logging in never scans the whole table
+    client
+        .execute(
+            "delete from sessions where uid = $1 and expires < now()",
+            &[&uid],
+        )
+        .await?;
+    client
+        .execute(
+            "insert into sessions (id, uid, expires, ip, user_agent) \
+             values ($1, $2, now() + make_interval(secs => $3), $4, $5)",
+            &[&id, &uid, &lifetime, &ip, &user_agent],
+        )
+        .await?;
+    identity.remember(id);
+    Ok(())
+}
+
+// resolves the session of the current request together with the roles of its
+// user, recording when and from where it was last seen, and drops the cookie if
+// the session was revoked or expired
+// takes the connection of the caller, so a request never holds two at once
+pub async fn user(
+    req: &HttpRequest,
+    identity: &Identity,
+    client: &psql::Client,
+) -> Result<Option<User>> {
+    let id = match identity.identity() {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let row = client
+        .query_opt(
+            "select users.id, username, firstname, lastname, email, \
+             exists(select 1 from employees where employees.uid = users.id) as employee, \
+             exists(select 1 from admins where admins.uid = users.id) as admin \
+             from sessions join users on users.id = sessions.uid \
+             where sessions.id = $1 and sessions.expires > now()",
+            &[&id],
+        )
+        .await?;
+    match row {
+        Some(row) => {
+            let (ip, user_agent) = client_info(req);
+            client
+                .execute(
+                    "update sessions set last_seen = now(), ip = $2, user_agent = $3 \
+                     where id = $1",
+                    &[&id, &ip, &user_agent],
+                )
+                .await?;
+            Ok(Some(User {
+                id: row.get("id"),
+                username: row.get("username"),
+                firstname: row.get("firstname"),
+                lastname: row.get("lastname"),
+                email: row.get("email"),
+                employee: row.get("employee"),
+                admin: row.get("admin"),
+            }))
+        }
+        None => {
+            identity.forget();
+            Ok(None)
+        }
+    }
+}
+
+pub async fn revoke<'a>(identity: &Identity, data: &ServerData<'a>) -> Result<()> {
+    if let Some(id) = identity.identity() {
+        data.client()
+            .await?
+            .execute("delete from sessions where id = $1", &[&id])
+            .await?;
+    }
+    identity.forget();
+    Ok(())
+}
+
+// revokes every session of the user except the one making the request
+pub async fn revoke_others(identity: &Identity, client: &psql::Client, uid: i32) -> Result<()> {
+    let id = identity.identity().unwrap_or_else(String::new);
+    client
+        .execute(
+            "delete from sessions where id != $1 and uid = $2",
+            &[&id, &uid],
+        )
+        .await?;
+    Ok(())
+}
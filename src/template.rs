@@ -1,557 +1,536 @@
-use std::convert::TryFrom;
-use std::fmt::Write;
-use std::str::FromStr;
-
-use actix_identity::Identity;
-use futures::future;
-use futures::TryFutureExt;
-use pulldown_cmark as md;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
 use tokio::fs;
 use tokio_postgres as psql;
 
+use crate::config::MarkdownConfig;
 use crate::error::{Error, Result};
 use crate::i18n::Language;
+use crate::markdown;
 use crate::path::PublicPath;
+use crate::web::ServerData;
+
+mod context;
+mod parse;
+mod patterns;
+mod registry;
+
+use parse::{Condition, Field, Node};
+
+pub use context::{RenderContext, User};
+pub use parse::{Compiled, Reference, Template, TemplateCache};
+pub use registry::{Pattern, PatternHandler, Registry};
 
+// what a directive produced: markup built by us, or text that came from the
+// database or a user and is escaped unless the template says `raw(...)`
 #[derive(Debug, Clone)]
-enum Pattern {
-    Empty,
-    Login,
-    Editor,
-    Admin,
-    Drafts,
-    AdminPanel,
-    Me(String),
-    Path(String),
-    Positional(usize),
-    L10n(String),
-    ArticlePositional(usize),
-    PreviewLatest(usize),
-    ArticleLatest(usize),
-    PreviewTitle(String),
-    ArticleTitle(String),
-    Maybe(Box<Pattern>),
+pub enum Output {
+    Html(String),
+    Text(String),
+}
+
+impl Output {
+    pub fn into_html(self) -> String {
+        match self {
+            Output::Html(html) => html,
+            Output::Text(text) => escape(&text),
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        match self {
+            Output::Html(text) | Output::Text(text) => text,
+        }
+    }
+
+    // `raw(...)`, inserted as it is
+    pub fn raw(self) -> Output {
+        Output::Html(self.into_string())
+    }
+
+    // `markdown(...)`, rendered following the policy of the configuration
+    pub fn markdown(self, config: &MarkdownConfig) -> Output {
+        Output::Html(markdown::render(&self.into_string(), config))
+    }
 }
 
-async fn author(client: &psql::Client, uid: i32) -> Result<Option<String>> {
-    let user = client
-        .query_opt(
-            "select firstname, lastname, username from users where id = $1",
-            &[&uid],
+// makes untrusted text safe to embed in html, including inside attributes
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '{' => escaped.push_str("&#123;"),
+            '}' => escaped.push_str("&#125;"),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn credit(lang: &Language, author: Option<&str>) -> String {
+    author
+        .map(|author| {
+            let args = [("author", escape(author))];
+            format!(" {}", lang.format("by_author", &args))
+        })
+        .unwrap_or_else(String::new)
+}
+
+// article bodies are inserted as they are, directives in them are not expanded
+async fn contents(data: &ServerData<'_>, path: &str) -> Result<String> {
+    let path = (PublicPath::with_root(&data.config.public) / path)?;
+    if !path.exists() {
+        return Err(Error::ResourceNotFound(path.to_string_lossy().to_string()));
+    }
+    let text = fs::read_to_string(&path).await?;
+    if path.extension() == Some("md".as_ref()) {
+        Ok(markdown::render(&text, &data.config.markdown))
+    } else {
+        Ok(text)
+    }
+}
+
+// the first paragraph of an article, for its description
+async fn description(data: &ServerData<'_>, path: &str) -> Result<String> {
+    let path = (PublicPath::with_root(&data.config.public) / path)?;
+    if path.extension() != Some("md".as_ref()) {
+        return Ok(String::new());
+    }
+    let text = fs::read_to_string(&path).await?;
+    Ok(markdown::summary(&text))
+}
+
+// an article, as bound to the variable of a `for`
+#[derive(Debug, Clone)]
+pub struct Article {
+    path: String,
+    title: String,
+    date: String,
+    author: Option<String>,
+    // what it's written in, not necessarily the language of the page
+    lang: String,
+}
+
+impl Article {
+    // from a row of a `translated` query
+    async fn from_row(cx: &RenderContext<'_>, row: &psql::Row) -> Result<Self> {
+        Ok(Self {
+            path: row.get::<_, &str>("path").to_string(),
+            title: row.get::<_, &str>("title").to_string(),
+            date: row.get::<_, &str>("date").to_string(),
+            author: cx.author(row.get::<_, i32>("author")).await?,
+            lang: row.get::<_, &str>("lang").to_string(),
+        })
+    }
+}
+
+// one row per article, in the first language of `$1` it was translated to,
+// otherwise as it was first written
+// articles from before translations count as written in `$2`, `filter`
+// narrows down the articles and translations to pick from
+// `published` is when the first version was, so translating doesn't reorder
+fn translated(filter: &str) -> String {
+    format!(
+        "select distinct on (coalesce(original, id)) id, title, path, author, \
+         coalesce(lang, $2) as lang, to_char(cdate, 'yyyy-mm-dd') as date, \
+         (select cdate from articles as first \
+          where first.id = coalesce(articles.original, articles.id)) as published \
+         from articles where {} \
+         order by coalesce(original, id), \
+         array_position($1::text[], coalesce(lang, $2)) nulls last, original nulls first",
+        filter
+    )
+}
+
+type Scope = [(String, Article)];
+
+fn preview(lang: &Language, article: &Article) -> String {
+    let by_author = credit(lang, article.author.as_deref());
+    format!(
+        "<article lang=\"{}\"><h2><a href=\"{}\">{}</a></h2>{}{}</article>",
+        escape(&article.lang),
+        escape(&article.path),
+        escape(&article.title),
+        article.date,
+        by_author,
+    )
+}
+
+// an article with its contents, as on its own page
+async fn full(data: &ServerData<'_>, lang: &Language, article: &Article) -> Result<String> {
+    let contents = contents(data, &article.path).await?;
+    Ok(format!(
+        "<article lang=\"{}\"><h1>{}</h1>{}{}<br/>{}</article>",
+        escape(&article.lang),
+        escape(&article.title),
+        article.date,
+        credit(lang, article.author.as_deref()),
+        contents,
+    ))
+}
+
+async fn latest(cx: &RenderContext<'_>, count: usize) -> Result<Vec<Article>> {
+    let query = format!(
+        "select * from ({}) as articles order by published desc limit $3",
+        translated("true")
+    );
+    let rows = cx
+        .client()
+        .await?
+        .query(
+            &query[..],
+            &[&cx.chain(), &cx.data.config.default_lang, &(count as i64)],
         )
         .await?;
-    match user {
-        Some(user) => {
-            let firstname = user.get::<_, Option<&str>>("firstname");
-            let lastname = user.get::<_, Option<&str>>("lastname");
-            let username = user.get::<_, &str>("username");
-            match (firstname, lastname) {
-                (Some(first), Some(last)) => {
-                    Ok(Some(format!("{} \"{}\" {}", first, username, last)))
-                }
-                (Some(first), None) => Ok(Some(format!("{} \"{}\"", first, username))),
-                (None, Some(last)) => Ok(Some(format!("\"{}\" {}", username, last))),
-                _ => Ok(Some(username.to_string())),
+    let mut articles = Vec::with_capacity(rows.len());
+    for row in &rows {
+        articles.push(Article::from_row(cx, row).await?);
+    }
+    Ok(articles)
+}
+
+async fn holds(cx: &RenderContext<'_>, mut cond: &Condition) -> Result<bool> {
+    let mut negate = false;
+    while let Condition::Not(inner) = cond {
+        negate = !negate;
+        cond = inner;
+    }
+    let holds = match cond {
+        Condition::Lang(code) => cx.lang.code() == code,
+        Condition::LoggedIn => cx.user().await?.is_some(),
+        Condition::Employee => cx.user().await?.map_or(false, |user| user.employee),
+        Condition::Admin => cx.user().await?.map_or(false, |user| user.admin),
+        Condition::Not(_) => unreachable!("negations are unwrapped above"),
+    };
+    Ok(holds != negate)
+}
+
+// the blocks a page fills in, by name
+type Blocks<'a> = HashMap<&'a str, &'a [Node]>;
+
+// includes nested deeper than this are most likely including themselves
+const MAX_DEPTH: usize = 16;
+
+// renders the template at `path`, compiling it first unless it's cached
+pub async fn render<P: AsRef<Path>>(cx: &RenderContext<'_>, path: P) -> Result<String> {
+    let template = cx.data.templates.get(path).await?;
+    render_template(cx, template, &Blocks::new(), &[], 0).await
+}
+
+// a template extending a layout renders the layout instead, with its blocks
+// filled in by the ones of the template
+// blocks already filled in by a page further down take precedence
+fn render_template<'a>(
+    cx: &'a RenderContext<'a>,
+    template: Arc<Template>,
+    blocks: &'a Blocks<'a>,
+    scope: &'a Scope,
+    depth: usize,
+) -> LocalBoxFuture<'a, Result<String>> {
+    async move {
+        let layout = match &template.extends {
+            Some(layout) => layout,
+            None => return render_nodes(cx, &template.nodes, blocks, scope, depth).await,
+        };
+        let path = (PublicPath::with_root(&cx.data.config.public) / &**layout)?;
+        if depth >= MAX_DEPTH {
+            return Err(Error::TemplateDepth(path.to_string_lossy().to_string()));
+        }
+        let mut inner = blocks.clone();
+        for node in &template.nodes {
+            if let Node::Block(name, nodes) = node {
+                inner.entry(&name[..]).or_insert(&nodes[..]);
             }
         }
-        None => Ok(None),
+        let layout = cx.data.templates.get(&*path).await?;
+        render_template(cx, layout, &inner, scope, depth + 1).await
     }
+    .boxed_local()
 }
 
-impl FromStr for Pattern {
-    type Err = Error;
-
-    fn from_str(pattern: &str) -> Result<Self> {
-        if pattern.is_empty() {
-            Ok(Pattern::Empty)
-        } else if pattern == "login" {
-            Ok(Pattern::Login)
-        } else if pattern == "editor" {
-            Ok(Pattern::Editor)
-        } else if pattern == "admin" {
-            Ok(Pattern::Admin)
-        } else if pattern == "drafts" {
-            Ok(Pattern::Drafts)
-        } else if pattern == "admin-panel" {
-            Ok(Pattern::AdminPanel)
-        } else if pattern.starts_with("me.") {
-            Ok(Pattern::Me(pattern[3..].to_string()))
-        } else if pattern.starts_with('/') {
-            Ok(Pattern::Path(pattern[1..].to_string()))
-        } else if pattern.starts_with('%') {
-            Ok(Pattern::Positional(pattern[1..].parse()?))
-        } else if pattern.starts_with("l10n(") {
-            let start = "l10n(".len();
-            let end = pattern.len() - 1;
-            if &pattern[end..] != ")" {
-                return Err(Error::InvalidPattern(pattern.to_string()));
-            }
-            let sub = &pattern[start..end];
-            Ok(Pattern::L10n(sub.to_string()))
-        } else if pattern.starts_with("article%") {
-            Ok(Pattern::ArticlePositional(
-                pattern["article%".len()..].parse()?,
-            ))
-        } else if pattern.starts_with("preview~") {
-            Ok(Pattern::PreviewLatest(pattern["preview~".len()..].parse()?))
-        } else if pattern.starts_with("article~") {
-            Ok(Pattern::ArticleLatest(pattern["article~".len()..].parse()?))
-        } else if pattern.starts_with("preview ") {
-            Ok(Pattern::PreviewTitle(
-                pattern["preview ".len()..].to_string(),
-            ))
-        } else if pattern.starts_with("article ") {
-            Ok(Pattern::ArticleTitle(
-                pattern["article ".len()..].to_string(),
-            ))
-        } else if pattern.starts_with("maybe(") {
-            let start = "maybe(".len();
-            let end = pattern.len() - 1;
-            if &pattern[end..] != ")" {
-                return Err(Error::InvalidPattern(pattern.to_string()));
-            }
-            let sub = &pattern[start..end];
-            Ok(Pattern::Maybe(Box::new(sub.parse()?)))
-        } else {
-            Err(Error::InvalidPattern(pattern.to_string()))
+// boxed, because includes recurse
+fn render_nodes<'a>(
+    cx: &'a RenderContext<'a>,
+    nodes: &'a [Node],
+    blocks: &'a Blocks<'a>,
+    scope: &'a Scope,
+    depth: usize,
+) -> LocalBoxFuture<'a, Result<String>> {
+    async move {
+        let mut output = String::new();
+        for node in nodes {
+            let value = evaluate(cx, node, blocks, scope, depth).await?;
+            output.push_str(&value.into_html());
         }
+        Ok(output)
     }
+    .boxed_local()
 }
 
-impl Pattern {
-    pub async fn to_string_nonrecursive(
-        self,
-        identity: &Identity,
-        client: &psql::Client,
-        lang: &Language,
-        args: &[String],
-    ) -> Result<String> {
-        match self {
-            Pattern::Empty => Ok(String::new()),
-            Pattern::Login => {
-                match identity.identity() {
-                    Some(identity) => {
-                        Ok(format!("<span class=\"float-right\"><a href=\"/auth/logout.html\">{{{{{{l10n(logout)}}}}}}</a></span> \
-                                    <span class=\"float-right\"><a href=\"/account/me.html\">{{{{{{l10n(logged_in_as)}}}}}}: {}</a></span>", identity))
-                    }
-                    None => {
-                        Ok("<span class=\"float-right\"><a href=\"/login.html\">{{{l10n(login)}}}</a></span> \
-                            <span class=\"float-right\"><a href=\"/create.html\">{{{l10n(register)}}}</a></span>".to_string())
-                    }
-                }
-            }
-            Pattern::Editor => {
-                match identity.identity() {
-                    Some(identity) => {
-                        // only employees are allowed to make new articles
-                        let user = client.query_opt(
-                            "select employees.id from employees where employees.uid = \
-                             (select users.id as uid from users where username = $1)",
-                            &[&identity]
-                        ).await?;
-                        if user.is_some() {
-                            Ok("<span class=\"float-right\"><a href=\"/account/editor.html\">{{{l10n(new_article)}}}</a></span>".to_string())
-                        } else {
-                            Err(Error::AuthorizationFailed)
-                        }
-                    }
-                    None => {
-                        Err(Error::AuthorizationFailed)
-                    }
+fn evaluate<'a>(
+    cx: &'a RenderContext<'a>,
+    node: &'a Node,
+    blocks: &'a Blocks<'a>,
+    scope: &'a Scope,
+    depth: usize,
+) -> LocalBoxFuture<'a, Result<Output>> {
+    async move {
+        match node {
+            Node::Text(text) => Ok(Output::Html(text.clone())),
+            // messages are html, values are escaped unless they are html themselves
+            Node::L10n(key, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for (name, node) in args {
+                    let value = evaluate(cx, node, blocks, scope, depth).await?;
+                    values.push((&name[..], value.into_html()));
                 }
+                Ok(Output::Html(cx.lang.format(key, &values)))
             }
-            Pattern::Admin => {
-                match identity.identity() {
-                    Some(identity) => {
-                        // only employees are allowed to make new articles
-                        let user = client.query_opt(
-                            "select admins.id from admins where admins.uid = \
-                             (select users.id as uid from users where username = $1)",
-                            &[&identity]
-                        ).await?;
-                        if user.is_some() {
-                            Ok("<span class=\"float-right\"><a href=\"/account/admin.html\">{{{l10n(admin_panel)}}}</a></span>".to_string())
-                        } else {
-                            Err(Error::AuthorizationFailed)
-                        }
-                    }
-                    None => {
-                        Err(Error::AuthorizationFailed)
-                    }
+            Node::Include(path) => {
+                let path = (PublicPath::with_root(&cx.data.config.public) / &**path)?;
+                if depth >= MAX_DEPTH {
+                    return Err(Error::TemplateDepth(path.to_string_lossy().to_string()));
                 }
+                let template = cx.data.templates.get(&*path).await?;
+                let html = render_template(cx, template, blocks, scope, depth + 1).await?;
+                Ok(Output::Html(html))
             }
-            Pattern::Drafts => {
-                match identity.identity() {
-                    Some(identity) => {
-                        let drafts = client.query(
-                            "select id, path, title from drafts where drafts.author = \
-                             (select users.id as author from users where username = $1)",
-                            &[&identity]
-                        ).await?;
-                        if drafts.len() > 0 {
-                            let mut select = format!("<select oninput=\"load_draft()\" id=\"draft-select\" name=\"draft-select\" size=\"{}\">\n", drafts.len().min(5).max(2));
-                            for draft in drafts {
-                                let value = draft.get::<_, i32>("id");
-                                let mut title = draft.get::<_, Option<&str>>("title").unwrap_or("&lt;untitled&gt;");
-                                if title.is_empty() {
-                                    title = "&lt;untitled&gt;";
-                                }
-                                write!(select, "<option value=\"{}\">{}</option>\n", value, title).expect("couldn't write to string");
-                            }
-                            write!(select, "</select>\n").expect("couldn't write to string");
-                            Ok(select)
-                        } else {
-                            Ok(String::new())
-                        }
-                    }
-                    None => {
-                        Err(Error::AuthorizationFailed)
-                    }
-                }
-            }
-            Pattern::AdminPanel => {
-                match identity.identity() {
-                    Some(identity) => {
-                        let admin = client.query_opt(
-                            "select id from admins where uid = \
-                             (select id as uid from users where username = $1)",
-                            &[&identity]
-                        ).await?;
-                        if admin.is_none() {
-                            return Err(Error::AuthorizationFailed);
-                        }
-
-                        let users = client.query(
-                            "select id, username, firstname, lastname, email from users",
-                            &[]
-                        ).await?;
-                        let mut select = format!("<table>\n");
-                        write!(select, "<tr>\n").expect("couldn't write to string");
-                        write!(select, "<th>UID</th>\n").expect("couldn't write to string");
-                        write!(select, "<th>{{{{{{l10n(account_username)}}}}}}</th>\n").expect("couldn't write to string");
-                        write!(select, "<th>{{{{{{l10n(account_firstname)}}}}}}</th>\n").expect("couldn't write to string");
-                        write!(select, "<th>{{{{{{l10n(account_lastname)}}}}}}</th>\n").expect("couldn't write to string");
-                        write!(select, "<th>{{{{{{l10n(account_email)}}}}}}</th>\n").expect("couldn't write to string");
-                        write!(select, "<th>{{{{{{l10n(account_isemployee)}}}}}}</th>\n").expect("couldn't write to string");
-                        write!(select, "<th>{{{{{{l10n(account_isadmin)}}}}}}</th>\n").expect("couldn't write to string");
-                        write!(select, "</tr>\n").expect("couldn't write to string");
-                        for user in users {
-                            let id = user.get::<_, i32>("id");
-                            let isadmin = client
-                                .query_opt(
-                                    "select id from admins where uid = \
-                                    (select id as uid from users where id = $1)",
-                                    &[&id]
-                                )
-                                .await?
-                                .is_some();
-                            let isemployee = client
-                                .query_opt(
-                                    "select id from employees where uid = \
-                                    (select id as uid from users where id = $1)",
-                                    &[&id]
-                                )
-                                .await?
-                                .is_some();
-                            let isadmin = if isadmin { "checked=\"checked\"" } else { "" };
-                            let isemployee = if isemployee { "checked=\"checked\"" } else { "" };
-                            write!(select, "<tr>\n").expect("couldn't write to string");
-                            write!(select, "<td>{}</td>\n", id).expect("couldn't write to string");
-                            write!(select, "<td>{}</td>\n", user.get::<_, &str>("username")).expect("couldn't write to string");
-                            write!(select, "<td>{}</td>\n", user.get::<_, Option<&str>>("firstname").unwrap_or("")).expect("couldn't write to string");
-                            write!(select, "<td>{}</td>\n", user.get::<_, Option<&str>>("lastname").unwrap_or("")).expect("couldn't write to string");
-                            write!(select, "<td><a href=\"mailto:{0}\">{0}</a></td>\n", user.get::<_, &str>("email")).expect("couldn't write to string");
-                            write!(select, "<td><form><input type=\"checkbox\" {} oninput=\"make_employee(this, {})\"/></form></td>\n", isemployee, id).expect("couldn't write to string");
-                            write!(select, "<td><form><input type=\"checkbox\" {} oninput=\"make_admin(this, {})\"/></form></td>\n", isadmin, id).expect("couldn't write to string");
-                            write!(select, "</tr>\n").expect("couldn't write to string");
-                        }
-                        write!(select, "</table>\n").expect("couldn't write to string");
-                        Ok(select)
-                    }
-                    None => {
-                        Err(Error::AuthorizationFailed)
-                    }
-                }
+            // arguments come from the request or the database, never from a template file
+            Node::Positional(pos) => pos
+                .checked_sub(1)
+                .and_then(|idx| cx.args.get(idx))
+                .cloned()
+                .map(Output::Text)
+                .ok_or_else(|| Error::ResourceNotFound(format!("%{}", pos))),
+            Node::Dynamic(name, arg) => {
+                let handler = cx
+                    .data
+                    .templates
+                    .registry()
+                    .get(name)
+                    .ok_or_else(|| Error::InvalidPattern(name.clone()))?;
+                handler.render(cx, arg).await
             }
-            Pattern::Me(field) => {
-                if field == "pwhash" {
-                    Ok("No passwords for you!".to_string())
-                } else {
-                    match identity.identity() {
-                        Some(me) => {
-                            match client.query_opt("select * from users where username = $1", &[&me]).await? {
-                                Some(row) => Ok(row.get::<&str, &str>(&field).to_string()),
-                                None => Ok("".to_string()),
-                            }
-                        }
-                        None => Ok("".to_string()),
-                    }
-                }
+            Node::Maybe(node) => Ok(evaluate(cx, node, blocks, scope, depth)
+                .await
+                .unwrap_or_else(|_| Output::Html(String::new()))),
+            Node::Raw(node) => Ok(evaluate(cx, node, blocks, scope, depth).await?.raw()),
+            Node::Markdown(node) => {
+                let value = evaluate(cx, node, blocks, scope, depth).await?;
+                Ok(value.markdown(&cx.data.config.markdown))
             }
-            Pattern::Path(path) => {
-                let path = PublicPath::try_from(path)?;
-                let text = fs::read_to_string(&path).await?;
-                if path.extension() == Some("md".as_ref()) {
-                    let parser = md::Parser::new_ext(&text, md::Options::all());
-                    let mut html = String::new();
-                    md::html::push_html(&mut html, parser);
-                    Ok(html)
-                } else {
-                    Ok(text)
-                }
+            Node::If(cond, then, alt) => {
+                let nodes = if holds(cx, cond).await? { then } else { alt };
+                let html = render_nodes(cx, nodes, blocks, scope, depth).await?;
+                Ok(Output::Html(html))
             }
-            Pattern::Positional(pos) => {
-                let path = args
-                    .get(pos - 1)
-                    .ok_or_else(|| Error::ResourceNotFound(format!("%{}", pos)))?;
-                let path = PublicPath::try_from(&**path)?;
-                let text = fs::read_to_string(&path).await?;
-                if path.extension() == Some("md".as_ref()) {
-                    let parser = md::Parser::new_ext(&text, md::Options::all());
-                    let mut html = String::new();
-                    md::html::push_html(&mut html, parser);
-                    Ok(html)
-                } else {
-                    Ok(text)
+            Node::For(var, count, body) => {
+                let mut html = String::new();
+                for article in latest(cx, *count).await? {
+                    let mut inner = scope.to_vec();
+                    inner.push((var.clone(), article));
+                    html.push_str(&render_nodes(cx, body, blocks, &inner, depth).await?);
                 }
+                Ok(Output::Html(html))
             }
-            Pattern::L10n(key) => {
-                Ok(lang[&key].to_string())
-            }
-            Pattern::ArticlePositional(pos) => {
-                let path = args
-                    .get(pos - 1)
-                    .ok_or_else(|| Error::ResourceNotFound(format!("%{}", pos)))?;
-                let args: &[&(dyn psql::types::ToSql + Sync)] = &[path];
-                let article = client
-                    .query_one("select title, to_char(cdate, 'yyyy-mm-dd') as date, author from articles where path = $1", args);
-                let contents = article
-                    .map_err(From::from)
-                    .and_then(async move |article| {
-                        let path = PublicPath::try_from(&**path)?;
-                        if path.exists() {
-                            let text = fs::read_to_string(&path)
-                                .await?;
-                            if path.extension() == Some("md".as_ref()) {
-                                let parser = md::Parser::new_ext(&text, md::Options::all());
-                                let mut html = String::new();
-                                md::html::push_html(&mut html, parser);
-                                Ok((article, html))
-                            } else {
-                                Ok((article, text))
-                            }
-                        } else {
-                            Err(Error::ResourceNotFound(path.to_string_lossy().to_string()))
-                        }
-                    });
-                contents.and_then(async move |(article, contents)| {
-                    let by_author = author(client, article.get::<_, i32>("author")).await?.map(|author| format!(" {{{{{{l10n(by_author)}}}}}} {}", author)).unwrap_or_else(String::new);
-                    Ok(format!(
-                        "<article><h1>{}</h1>{}{}<br/>{}</article>",
-                        article.get::<_, &str>("title"),
-                        article.get::<_, &str>("date"),
-                        by_author,
-                        contents,
-                    ))
-                }).await
-            }
-            Pattern::PreviewLatest(no) => {
-                let rows = client
-                    .query("select title, path, to_char(cdate, 'yyyy-mm-dd') as date, author from articles order by cdate", &[])
-                    .await?;
-                let article = rows.len().checked_sub(no).and_then(|no| rows.get(no)).ok_or_else(|| Error::ResourceNotFound(format!("preview~{}", no)))?;
-                let by_author = author(client, article.get::<_, i32>("author")).await?.map(|author| format!(" {{{{{{l10n(by_author)}}}}}} {}", author)).unwrap_or_else(String::new);
-                Ok(format!(
-                    "<article><h2><a href=\"{}\">{}</a></h2>{}{}</article>",
-                    article.get::<_, &str>("path"),
-                    article.get::<_, &str>("title"),
-                    article.get::<_, &str>("date"),
-                    by_author,
-                ))
-            }
-            Pattern::ArticleLatest(no) => {
-                let rows = client
-                    .query("select path, title, to_char(cdate, 'yyyy-mm-dd') as date, author from articles order by cdate", &[])
-                    .await?;
-                let article = rows.len().checked_sub(no).and_then(|no| rows.get(no));
-                let contents = article.map(|article| {
-                    future::ok(article)
-                        .and_then(async move |article| {
-                            let path = article.get::<_, &str>("path");
-                            let path = PublicPath::try_from(path)?;
-                            if path.exists() {
-                                let text = fs::read_to_string(&path)
-                                    .await?;
-                                if path.extension() == Some("md".as_ref()) {
-                                    let parser = md::Parser::new_ext(&text, md::Options::all());
-                                    let mut html = String::new();
-                                    md::html::push_html(&mut html, parser);
-                                    Ok((article, html))
-                                } else {
-                                    Ok((article, text))
-                                }
-                            } else {
-                                Err(Error::ResourceNotFound(path.to_string_lossy().to_string()))
-                            }
-                        })
-                });
-                if let Some(contents) = contents {
-                    contents.and_then(async move |(article, contents)| {
-                        let by_author = author(client, article.get::<_, i32>("author")).await?.map(|author| format!(" {{{{{{l10n(by_author)}}}}}} {}", author)).unwrap_or_else(String::new);
-                        Ok(format!(
-                            "<article><h1>{}</h1>{}{}<br/>{}</article>",
-                            article.get::<_, &str>("title"),
-                            article.get::<_, &str>("date"),
-                            by_author,
-                            contents,
-                        ))
-                    }).await
-                } else {
-                    Ok(String::new())
+            // counts towards the depth, a block filled in with itself would never end
+            Node::Block(name, default) => {
+                if depth >= MAX_DEPTH {
+                    return Err(Error::TemplateDepth(format!("block {}", name)));
                 }
+                let nodes = blocks.get(&name[..]).copied().unwrap_or(&default[..]);
+                let html = render_nodes(cx, nodes, blocks, scope, depth + 1).await?;
+                Ok(Output::Html(html))
             }
-            Pattern::PreviewTitle(title) => {
-                let article = client
-                    .query_one("select title, path, to_char(cdate, 'yyyy-mm-dd') as date, author from articles where title = $1", &[&title])
-                    .await?;
-                let by_author = author(client, article.get::<_, i32>("author")).await?.map(|author| format!(" {{{{{{l10n(by_author)}}}}}} {}", author)).unwrap_or_else(String::new);
-                Ok(format!(
-                    "<article><h2><a href=\"{}\">{}</a></h2>{}{}</article>",
-                    article.get::<_, &str>("path"),
-                    article.get::<_, &str>("title"),
-                    article.get::<_, &str>("date"),
-                    by_author,
-                ))
-            }
-            Pattern::ArticleTitle(title) => {
-                let args: &[&(dyn psql::types::ToSql + Sync)] = &[&title];
-                let article = client
-                    .query_one("select title, to_char(cdate, 'yyyy-mm-dd') as date, author from articles where title = $1", args);
-                let contents = article
-                    .map_err(From::from)
-                    .and_then(async move |article| {
-                        let path = article.get::<_, &str>("path");
-                        let path = PublicPath::try_from(path)?;
-                        if path.exists() {
-                            let text = fs::read_to_string(&path)
-                                .await?;
-                            if path.extension() == Some("md".as_ref()) {
-                                let parser = md::Parser::new_ext(&text, md::Options::all());
-                                let mut html = String::new();
-                                md::html::push_html(&mut html, parser);
-                                Ok((article, html))
-                            } else {
-                                Ok((article, text))
-                            }
-                        } else {
-                            Err(Error::ResourceNotFound(path.to_string_lossy().to_string()))
-                        }
-                    });
-                contents.and_then(async move |(article, contents)| {
-                    let by_author = author(client, article.get::<_, i32>("author")).await?.map(|author| format!(" {{{{{{l10n(by_author)}}}}}} {}", author)).unwrap_or_else(String::new);
-                    Ok(format!(
-                        "<article><h1>{}</h1>{}{}<br/>{}</article>",
-                        article.get::<_, &str>("title"),
-                        article.get::<_, &str>("date"),
-                        by_author,
-                        contents,
-                    ))
-                }).await
-            }
-            Pattern::Maybe(_) => {
-                Err(Error::AsyncRecursion)
+            Node::Field(var, field) => {
+                let article = scope
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| name == var)
+                    .map(|(_, article)| article)
+                    .ok_or_else(|| Error::InvalidPattern(var.clone()))?;
+                Ok(match field {
+                    Field::Title => Output::Text(article.title.clone()),
+                    Field::Path => Output::Text(article.path.clone()),
+                    Field::Date => Output::Text(article.date.clone()),
+                    Field::Author => Output::Text(article.author.clone().unwrap_or_default()),
+                    Field::Lang => Output::Text(article.lang.clone()),
+                    Field::Preview => Output::Html(preview(cx.lang, article)),
+                })
             }
         }
     }
+    .boxed_local()
+}
 
-    pub async fn to_string(
-        self,
-        identity: &Identity,
-        client: &psql::Client,
-        lang: &Language,
-        args: &[String],
-    ) -> Result<String> {
-        match self {
-            Pattern::Maybe(opt) => Ok(opt
-                .to_string_nonrecursive(identity, client, lang, args)
-                .await
-                .unwrap_or_else(|_| String::new())),
-            other => {
-                other
-                    .to_string_nonrecursive(identity, client, lang, args)
-                    .await
-            }
+#[cfg(test)]
+mod tests {
+    use actix_identity::Identity;
+    use actix_web::test::TestRequest;
+    use actix_web::FromRequest;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::i18n::Languages;
+    use crate::pool::Pool;
+
+    // a site in a directory of its own with `files` in `public`, nothing in it
+    // may need the database, which is never connected to
+    fn site(name: &str, files: &[(&str, &str)]) -> ServerData<'static> {
+        let root = std::env::temp_dir().join(format!("circus-{}-{}", name, std::process::id()));
+        for (path, text) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().expect("files are in the root"))
+                .expect("couldn't create directory");
+            std::fs::write(path, text).expect("couldn't write template");
         }
+        let mut config = Config::default();
+        config.public = root;
+        ServerData::new(
+            config.clone(),
+            Arc::new(Pool::new(config.clone(), String::new())),
+            Arc::new(Languages::new(HashMap::new())),
+            Arc::new(TemplateCache::new(
+                Registry::default(),
+                config.markdown.clone(),
+            )),
+        )
     }
 
-    pub async fn replace_at(
-        self,
-        identity: &Identity,
-        client: &psql::Client,
-        lang: &Language,
-        input: &mut String,
-        start: usize,
-        end: usize,
-        args: &[String],
-    ) -> Result<usize> {
-        let text = self.to_string(identity, client, lang, args).await?;
-        input.replace_range(start..(end + 3), &text);
-        Ok(text.len())
+    async fn render_page(data: &ServerData<'_>, path: &str) -> Result<String> {
+        let req = TestRequest::default().to_http_request();
+        let identity = Identity::extract(&req)
+            .await
+            .expect("couldn't extract identity");
+        let lang = lang();
+        let cx = RenderContext::new(&req, &identity, data, &lang).await?;
+        render(&cx, data.config.public.join(path)).await
     }
-}
 
-async fn replace_at(
-    identity: &Identity,
-    client: &psql::Client,
-    lang: &Language,
-    input: &mut String,
-    start: usize,
-    args: &[String],
-) -> Result<usize> {
-    if let Some(len) = &input[start..].find("}}}") {
-        let end = start + len;
-        let pattern = &input[(start + 3)..end];
-        let pattern = pattern.parse().unwrap_or(Pattern::Empty);
-        pattern
-            .replace_at(identity, client, lang, input, start, end, args)
-            .await
-    } else {
-        Ok(0)
+    fn lang() -> Language {
+        ron::de::from_str(r#"(code: "en", language: "English", t9n: {"by_author": "by {author}"})"#)
+            .expect("couldn't parse language")
     }
-}
 
-pub async fn search_replace(
-    identity: &Identity,
-    client: &psql::Client,
-    lang: &Language,
-    input: &mut String,
-    args: &[String],
-) -> Result<()> {
-    let mut i = 0;
-    loop {
-        match input[i..].find("{{{") {
-            Some(idx) => {
-                let len = replace_at(identity, client, lang, input, idx, args).await?;
-                i = idx + len;
-            }
-            None => break Ok(()),
+    fn article(title: &str, author: &str) -> Article {
+        Article {
+            path: "articles/foo.md".to_string(),
+            title: title.to_string(),
+            date: "2020-01-01".to_string(),
+            author: Some(author.to_string()),
+            lang: "en".to_string(),
         }
     }
-}
 
-pub async fn search_replace_recursive(
-    identity: &Identity,
-    client: &psql::Client,
-    lang: &Language,
-    input: &mut String,
-    args: &[String],
-) -> Result<()> {
-    loop {
-        match input.find("{{{") {
-            Some(idx) => {
-                replace_at(identity, client, lang, input, idx, args).await?;
+    #[test]
+    fn escapes_markup_and_directives() {
+        assert_eq!(
+            escape("<script>alert('{{{x}}}')</script> & \""),
+            "&lt;script&gt;alert(&#39;&#123;&#123;&#123;x&#125;&#125;&#125;&#39;)&lt;/script&gt; &amp; &quot;"
+        );
+    }
+
+    #[test]
+    fn text_is_escaped_html_is_not() {
+        let text = Output::Text("<b>{{{me.email}}}</b>".to_string());
+        assert_eq!(
+            text.into_html(),
+            "&lt;b&gt;&#123;&#123;&#123;me.email&#125;&#125;&#125;&lt;/b&gt;"
+        );
+        let html = Output::Html("<b>bold</b>".to_string());
+        assert_eq!(html.into_html(), "<b>bold</b>");
+    }
+
+    #[test]
+    fn raw_inserts_text_as_it_is() {
+        let text = Output::Text("<em>hi</em>".to_string());
+        assert_eq!(text.raw().into_html(), "<em>hi</em>");
+    }
+
+    #[test]
+    fn markdown_renders_and_sanitizes() {
+        let text = Output::Text("*hi* <script>alert(1)</script>".to_string());
+        let html = text.markdown(&MarkdownConfig::default()).into_html();
+        assert!(html.contains("<em>hi</em>"));
+        assert!(!html.contains("<script"));
+    }
+
+    #[test]
+    fn previews_escape_titles_and_authors() {
+        let html = preview(&lang(), &article("<script>{{{x}}}", "<b>{{{y}}}</b>"));
+        assert!(html.contains("&lt;script&gt;&#123;&#123;&#123;x"));
+        assert!(html.contains("by &lt;b&gt;&#123;&#123;&#123;y"));
+        assert!(!html.contains("<script>") && !html.contains("{{{"));
+    }
+
+    #[actix_rt::test]
+    async fn templates_including_themselves_stop() {
+        let data = site(
+            "depth",
+            &[
+                ("include.html", "x{{{/include.html}}}"),
+                ("extends.html", "{{{extends /extends.html}}}"),
+            ],
+        );
+        for page in &["include.html", "extends.html"] {
+            match render_page(&data, page).await {
+                Err(Error::TemplateDepth(_)) => {}
+                Err(err) => panic!("{}: {}", page, err),
+                Ok(html) => panic!("{}: {}", page, html),
             }
-            None => break Ok(()),
         }
     }
+
+    #[actix_rt::test]
+    async fn pages_fill_in_blocks_of_their_layout() {
+        let data = site(
+            "blocks",
+            &[
+                (
+                    "layout.html",
+                    "<title>{{{block title}}}Site{{{end}}}</title>\
+                     <main>{{{block main}}}empty{{{end}}}</main>",
+                ),
+                (
+                    "section.html",
+                    "{{{extends /layout.html}}}\n\
+                     {{{block title}}}Section{{{end}}}\n\
+                     {{{block main}}}section{{{end}}}",
+                ),
+                (
+                    "page.html",
+                    "{{{extends /section.html}}}\n{{{block title}}}Page{{{end}}}",
+                ),
+                ("bare.html", "{{{extends /layout.html}}}\n"),
+            ],
+        );
+        assert_eq!(
+            render_page(&data, "bare.html")
+                .await
+                .expect("couldn't render"),
+            "<title>Site</title><main>empty</main>"
+        );
+        assert_eq!(
+            render_page(&data, "section.html")
+                .await
+                .expect("couldn't render"),
+            "<title>Section</title><main>section</main>"
+        );
+        // the page further down wins, blocks it leaves out come from in between
+        assert_eq!(
+            render_page(&data, "page.html")
+                .await
+                .expect("couldn't render"),
+            "<title>Page</title><main>section</main>"
+        );
+    }
 }
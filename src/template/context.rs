@@ -0,0 +1,162 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use actix_identity::Identity;
+use actix_web::HttpRequest;
+
+use crate::error::{Error, Result};
+use crate::i18n::Language;
+use crate::pool::PooledClient;
+use crate::session;
+use crate::web::ServerData;
+
+use super::{translated, Article};
+
+// the logged in user, together with their roles
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub firstname: Option<String>,
+    pub lastname: Option<String>,
+    pub email: String,
+    pub employee: bool,
+    pub admin: bool,
+}
+
+// everything a template needs while rendering one request
+// the database connection is taken the first time it's needed and then kept,
+// and the user and the authors of articles are only looked up once as well
+pub struct RenderContext<'a> {
+    req: HttpRequest,
+    pub identity: &'a Identity,
+    pub data: &'a ServerData<'a>,
+    pub lang: &'a Language,
+    // the path of the request, e.g. `/account/me.html`
+    pub path: String,
+    // scheme and host the site is reached at, e.g. `https://circus.example.org`
+    pub origin: String,
+    pub args: Vec<String>,
+    client: RefCell<Option<Rc<PooledClient>>>,
+    user: RefCell<Option<Option<Rc<User>>>>,
+    authors: RefCell<HashMap<i32, Option<String>>>,
+    articles: RefCell<HashMap<String, Rc<Article>>>,
+}
+
+impl<'a> RenderContext<'a> {
+    pub async fn new(
+        req: &HttpRequest,
+        identity: &'a Identity,
+        data: &'a ServerData<'a>,
+        lang: &'a Language,
+    ) -> Result<RenderContext<'a>> {
+        let origin = match &data.config.url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let info = req.connection_info();
+                format!("{}://{}", info.scheme(), info.host())
+            }
+        };
+        Ok(Self {
+            req: req.clone(),
+            identity,
+            data,
+            lang,
+            path: req.path().to_string(),
+            origin,
+            args: Vec::new(),
+            client: RefCell::new(None),
+            user: RefCell::new(None),
+            authors: RefCell::new(HashMap::new()),
+            articles: RefCell::new(HashMap::new()),
+        })
+    }
+
+    // the arguments `{{{%N}}}` and `{{{article%N}}}` refer to
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    // pages that don't query the database never wait for a connection
+    pub async fn client(&self) -> Result<Rc<PooledClient>> {
+        if let Some(client) = &*self.client.borrow() {
+            return Ok(Rc::clone(client));
+        }
+        let client = Rc::new(self.data.client().await?);
+        *self.client.borrow_mut() = Some(Rc::clone(&client));
+        Ok(client)
+    }
+
+    // the languages articles are picked in, best first
+    pub fn chain(&self) -> Vec<String> {
+        self.data.config.chain(self.lang.code())
+    }
+
+    // looked up, and the session touched, the first time it's needed
+    pub async fn user(&self) -> Result<Option<Rc<User>>> {
+        if let Some(user) = &*self.user.borrow() {
+            return Ok(user.clone());
+        }
+        let user = session::user(&self.req, self.identity, &self.client().await?)
+            .await?
+            .map(Rc::new);
+        *self.user.borrow_mut() = Some(user.clone());
+        Ok(user)
+    }
+
+    // how the author of an article is credited, e.g. `Max "maxi" Mustermann`
+    pub async fn author(&self, uid: i32) -> Result<Option<String>> {
+        if let Some(author) = self.authors.borrow().get(&uid) {
+            return Ok(author.clone());
+        }
+        let user = self
+            .client()
+            .await?
+            .query_opt(
+                "select firstname, lastname, username from users where id = $1",
+                &[&uid],
+            )
+            .await?;
+        let author = user.map(|user| {
+            let firstname = user.get::<_, Option<&str>>("firstname");
+            let lastname = user.get::<_, Option<&str>>("lastname");
+            let username = user.get::<_, &str>("username");
+            match (firstname, lastname) {
+                (Some(first), Some(last)) => format!("{} \"{}\" {}", first, username, last),
+                (Some(first), None) => format!("{} \"{}\"", first, username),
+                (None, Some(last)) => format!("\"{}\" {}", username, last),
+                _ => username.to_string(),
+            }
+        });
+        self.authors.borrow_mut().insert(uid, author.clone());
+        Ok(author)
+    }
+
+    // the article at `path`, e.g. `articles/foobar.md`, or the translation of
+    // it in the language of the page
+    pub async fn article(&self, path: &str) -> Result<Rc<Article>> {
+        if let Some(article) = self.articles.borrow().get(path) {
+            return Ok(Rc::clone(article));
+        }
+        let query = translated(
+            "coalesce(original, id) = \
+             (select coalesce(original, id) from articles where path = $3)",
+        );
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &query[..],
+                &[&self.chain(), &self.data.config.default_lang, &path],
+            )
+            .await?
+            .ok_or_else(|| Error::ResourceNotFound(path.to_string()))?;
+        let article = Rc::new(Article::from_row(self, &row).await?);
+        self.articles
+            .borrow_mut()
+            .insert(path.to_string(), Rc::clone(&article));
+        Ok(article)
+    }
+}
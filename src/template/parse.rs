@@ -0,0 +1,671 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use tokio::fs;
+
+use crate::config::MarkdownConfig;
+use crate::error::{Error, Result};
+use crate::markdown;
+
+use super::Registry;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+    // `{{{/path}}}`, resolved relative to the public root
+    Include(String),
+    // `{{{%N}}}`, the N-th argument passed to the handler, inserted as text
+    Positional(usize),
+    // `{{{l10n(key, name: value, ...)}}}`, the values fill in the placeholders
+    // of the message and are directives themselves, e.g. `author: %1`
+    L10n(String, Vec<(String, Node)>),
+    // a pattern from the `Registry`, by name and argument
+    Dynamic(String, String),
+    // errors inside are swallowed and render as nothing
+    Maybe(Box<Node>),
+    // text is inserted without escaping it
+    Raw(Box<Node>),
+    // text is rendered as markdown instead of being escaped
+    Markdown(Box<Node>),
+    // `{{{if cond}}} ... {{{else}}} ... {{{end}}}`
+    If(Condition, Vec<Node>, Vec<Node>),
+    // `{{{for var in articles(N)}}} ... {{{end}}}`, over the N latest articles
+    For(String, usize, Vec<Node>),
+    // `{{{var.field}}}` inside of a `for` over `var`
+    Field(String, Field),
+    // `{{{block name}}} ... {{{end}}}`, filled in by a page extending the layout,
+    // otherwise the default inside is rendered
+    Block(String, Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    LoggedIn,
+    Employee,
+    Admin,
+    Lang(String),
+    Not(Box<Condition>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Path,
+    Date,
+    Author,
+    Lang,
+    Preview,
+}
+
+// what a template refers to outside of itself, checked by `circus-backend check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    Include(String),
+    L10n(String),
+}
+
+#[derive(Debug)]
+pub struct Template {
+    // `{{{extends /path}}}`, the layout the blocks of this template are put into
+    pub extends: Option<String>,
+    pub nodes: Vec<Node>,
+    // with the line they appear on
+    pub refs: Vec<(usize, Reference)>,
+}
+
+// a tag either is a node on its own or opens, splits or closes a block
+enum Tag {
+    Node(Node),
+    If(Condition),
+    For(String, usize),
+    Block(String),
+    Extends(String),
+    Else,
+    End,
+}
+
+enum Open {
+    If(Condition),
+    For(String, usize),
+    Block(String),
+}
+
+// a block that hasn't seen its `{{{end}}}` yet
+struct Frame {
+    open: Open,
+    line: usize,
+    nodes: Vec<Node>,
+    // `Some` once the `{{{else}}}` of an `if` was seen
+    alt: Option<Vec<Node>>,
+}
+
+// the argument of `name(...)`
+fn call<'a>(tag: &'a str, name: &str) -> Result<&'a str> {
+    if tag.ends_with(')') {
+        Ok(&tag[name.len()..tag.len() - 1])
+    } else {
+        Err(Error::InvalidPattern(tag.to_string()))
+    }
+}
+
+// splits at the commas outside of parentheses
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (idx, ch) in args.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(args[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(args[start..].trim());
+    parts
+}
+
+fn l10n(args: &str, vars: &[&str], registry: &Registry) -> Result<Node> {
+    let mut parts = split_args(args).into_iter();
+    let key = parts.next().unwrap_or("");
+    if key.is_empty() {
+        return Err(Error::InvalidPattern(args.to_string()));
+    }
+    let mut placeholders = Vec::new();
+    for part in parts {
+        let colon = part
+            .find(':')
+            .ok_or_else(|| Error::InvalidPattern(part.to_string()))?;
+        let name = part[..colon].trim();
+        if name.is_empty() {
+            return Err(Error::InvalidPattern(part.to_string()));
+        }
+        let value = node(part[colon + 1..].trim(), vars, registry)?;
+        placeholders.push((name.to_string(), value));
+    }
+    Ok(Node::L10n(key.to_string(), placeholders))
+}
+
+fn condition(cond: &str) -> Result<Condition> {
+    if cond.starts_with("not ") {
+        Ok(Condition::Not(Box::new(condition(
+            cond["not ".len()..].trim(),
+        )?)))
+    } else if cond == "logged-in" {
+        Ok(Condition::LoggedIn)
+    } else if cond == "employee" {
+        Ok(Condition::Employee)
+    } else if cond == "admin" {
+        Ok(Condition::Admin)
+    } else if cond.starts_with("lang(") {
+        Ok(Condition::Lang(call(cond, "lang(")?.to_string()))
+    } else {
+        Err(Error::InvalidPattern(cond.to_string()))
+    }
+}
+
+fn field(field: &str) -> Result<Field> {
+    match field {
+        "title" => Ok(Field::Title),
+        "path" => Ok(Field::Path),
+        "date" => Ok(Field::Date),
+        "author" => Ok(Field::Author),
+        "lang" => Ok(Field::Lang),
+        "preview" => Ok(Field::Preview),
+        _ => Err(Error::InvalidPattern(field.to_string())),
+    }
+}
+
+// `vars` are the loop variables of the enclosing `for`s
+fn node(tag: &str, vars: &[&str], registry: &Registry) -> Result<Node> {
+    if tag.is_empty() {
+        Ok(Node::Text(String::new()))
+    } else if tag.starts_with('/') {
+        Ok(Node::Include(tag[1..].to_string()))
+    } else if tag.starts_with('%') {
+        // arguments count from 1
+        match tag[1..].parse()? {
+            0 => Err(Error::InvalidPattern(tag.to_string())),
+            pos => Ok(Node::Positional(pos)),
+        }
+    } else if tag.starts_with("l10n(") {
+        l10n(call(tag, "l10n(")?, vars, registry)
+    } else if tag.starts_with("maybe(") {
+        Ok(Node::Maybe(Box::new(node(
+            call(tag, "maybe(")?,
+            vars,
+            registry,
+        )?)))
+    } else if tag.starts_with("raw(") {
+        Ok(Node::Raw(Box::new(node(
+            call(tag, "raw(")?,
+            vars,
+            registry,
+        )?)))
+    } else if tag.starts_with("markdown(") {
+        Ok(Node::Markdown(Box::new(node(
+            call(tag, "markdown(")?,
+            vars,
+            registry,
+        )?)))
+    } else {
+        let var = vars
+            .iter()
+            .find(|var| tag.starts_with(*var) && tag[var.len()..].starts_with('.'));
+        match var {
+            Some(var) => Ok(Node::Field(var.to_string(), field(&tag[var.len() + 1..])?)),
+            None => {
+                let (name, arg) = registry
+                    .lookup(tag)
+                    .ok_or_else(|| Error::InvalidPattern(tag.to_string()))?;
+                if let Some(handler) = registry.get(name) {
+                    handler.check(arg)?;
+                }
+                Ok(Node::Dynamic(name.to_string(), arg.to_string()))
+            }
+        }
+    }
+}
+
+fn tag(tag: &str, vars: &[&str], registry: &Registry) -> Result<Tag> {
+    if tag == "else" {
+        Ok(Tag::Else)
+    } else if tag == "end" {
+        Ok(Tag::End)
+    } else if tag.starts_with("if ") {
+        Ok(Tag::If(condition(tag["if ".len()..].trim())?))
+    } else if tag.starts_with("for ") {
+        let mut words = tag["for ".len()..].split_whitespace();
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some(var), Some("in"), Some(list), None) if list.starts_with("articles(") => {
+                Ok(Tag::For(var.to_string(), call(list, "articles(")?.parse()?))
+            }
+            _ => Err(Error::InvalidPattern(tag.to_string())),
+        }
+    } else if tag.starts_with("block ") {
+        let name = tag["block ".len()..].trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(Error::InvalidPattern(tag.to_string()));
+        }
+        Ok(Tag::Block(name.to_string()))
+    } else if tag.starts_with("extends ") {
+        let path = tag["extends ".len()..].trim();
+        if path.starts_with('/') {
+            Ok(Tag::Extends(path[1..].to_string()))
+        } else {
+            Err(Error::InvalidPattern(tag.to_string()))
+        }
+    } else {
+        Ok(Tag::Node(node(tag, vars, registry)?))
+    }
+}
+
+impl Frame {
+    fn new(open: Open, line: usize) -> Self {
+        Self {
+            open,
+            line,
+            nodes: Vec::new(),
+            alt: None,
+        }
+    }
+}
+
+fn references(node: &Node, line: usize, refs: &mut Vec<(usize, Reference)>) {
+    match node {
+        Node::Include(path) => refs.push((line, Reference::Include(path.clone()))),
+        Node::L10n(key, args) => {
+            refs.push((line, Reference::L10n(key.clone())));
+            for (_, node) in args {
+                references(node, line, refs);
+            }
+        }
+        Node::Maybe(node) | Node::Raw(node) | Node::Markdown(node) => references(node, line, refs),
+        _ => {}
+    }
+}
+
+fn blank(node: &Node) -> bool {
+    match node {
+        Node::Text(text) => text.trim().is_empty(),
+        _ => false,
+    }
+}
+
+// appends to the innermost open block, or the template itself
+fn push(nodes: &mut Vec<Node>, stack: &mut Vec<Frame>, node: Node) {
+    match stack.last_mut() {
+        Some(Frame { alt: Some(alt), .. }) => alt.push(node),
+        Some(frame) => frame.nodes.push(node),
+        None => nodes.push(node),
+    }
+}
+
+// a page extending a layout only consists of the blocks it fills in
+const OUTSIDE: &str = "only blocks may follow `extends`";
+
+impl Template {
+    // markdown is rendered to html first, so directives in it survive as plain text
+    pub fn compile<P: AsRef<Path>>(
+        path: P,
+        text: &str,
+        registry: &Registry,
+        policy: &MarkdownConfig,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let syntax = |line, desc: &str| {
+            Error::TemplateSyntax(path.display().to_string(), line, desc.to_string())
+        };
+        let html;
+        let text = if path.extension() == Some("md".as_ref()) {
+            html = markdown::render(text, policy);
+            &html
+        } else {
+            text
+        };
+
+        let mut nodes = Vec::new();
+        let mut refs = Vec::new();
+        let mut stack: Vec<Frame> = Vec::new();
+        let mut extends = None;
+        let mut defined = HashSet::new();
+        let mut rest = text;
+        let mut line = 1;
+        loop {
+            let start = rest.find("{{{").unwrap_or_else(|| rest.len());
+            if start > 0 {
+                let text = Node::Text(rest[..start].to_string());
+                if extends.is_some() && stack.is_empty() {
+                    if !blank(&text) {
+                        return Err(syntax(line, OUTSIDE));
+                    }
+                } else {
+                    push(&mut nodes, &mut stack, text);
+                }
+            }
+            line += rest[..start].matches('\n').count();
+            if start == rest.len() {
+                break;
+            }
+
+            let inner = &rest[start + 3..];
+            let end = inner
+                .find("}}}")
+                .ok_or_else(|| syntax(line, "unterminated `{{{`"))?;
+            let inner = &inner[..end];
+            let vars: Vec<&str> = stack
+                .iter()
+                .filter_map(|frame| match &frame.open {
+                    Open::For(var, _) => Some(&var[..]),
+                    Open::If(_) | Open::Block(_) => None,
+                })
+                .collect();
+            let parsed =
+                tag(inner.trim(), &vars, registry).map_err(|err| syntax(line, &err.to_string()))?;
+            match parsed {
+                Tag::Node(node) => {
+                    if extends.is_some() && stack.is_empty() && !blank(&node) {
+                        return Err(syntax(line, OUTSIDE));
+                    }
+                    references(&node, line, &mut refs);
+                    push(&mut nodes, &mut stack, node);
+                }
+                Tag::If(cond) => {
+                    if extends.is_some() && stack.is_empty() {
+                        return Err(syntax(line, OUTSIDE));
+                    }
+                    stack.push(Frame::new(Open::If(cond), line))
+                }
+                Tag::For(var, count) => {
+                    if extends.is_some() && stack.is_empty() {
+                        return Err(syntax(line, OUTSIDE));
+                    }
+                    stack.push(Frame::new(Open::For(var, count), line))
+                }
+                Tag::Block(name) => {
+                    if extends.is_some() && stack.is_empty() && !defined.insert(name.clone()) {
+                        return Err(syntax(line, &format!("block `{}` is defined twice", name)));
+                    }
+                    stack.push(Frame::new(Open::Block(name), line))
+                }
+                Tag::Extends(path) => {
+                    if extends.is_some() || !stack.is_empty() || !nodes.iter().all(blank) {
+                        return Err(syntax(line, "`extends` has to come before anything else"));
+                    }
+                    nodes.clear();
+                    refs.push((line, Reference::Include(path.clone())));
+                    extends = Some(path);
+                }
+                Tag::Else => match stack.last_mut() {
+                    Some(frame) if frame.alt.is_none() => match frame.open {
+                        Open::If(_) => frame.alt = Some(Vec::new()),
+                        Open::For(..) => return Err(syntax(line, "`else` inside of a `for`")),
+                        Open::Block(_) => return Err(syntax(line, "`else` inside of a `block`")),
+                    },
+                    _ => return Err(syntax(line, "`else` outside of an `if`")),
+                },
+                Tag::End => {
+                    let frame = stack
+                        .pop()
+                        .ok_or_else(|| syntax(line, "`end` without a block"))?;
+                    let node = match frame.open {
+                        Open::If(cond) => {
+                            Node::If(cond, frame.nodes, frame.alt.unwrap_or_else(Vec::new))
+                        }
+                        Open::For(var, count) => Node::For(var, count, frame.nodes),
+                        Open::Block(name) => Node::Block(name, frame.nodes),
+                    };
+                    push(&mut nodes, &mut stack, node);
+                }
+            }
+            line += inner.matches('\n').count();
+            rest = &rest[start + 3 + end + 3..];
+        }
+        if let Some(frame) = stack.last() {
+            return Err(syntax(frame.line, "block is never closed with `end`"));
+        }
+
+        Ok(Self {
+            extends,
+            nodes,
+            refs,
+        })
+    }
+}
+
+// the templates of a `TemplateCache` by path, with the mtime they were compiled at
+pub type Compiled = HashMap<PathBuf, (SystemTime, Arc<Template>)>;
+
+// compiled templates, shared by every worker
+// a template is compiled again as soon as the file on disk changes, if that
+// fails the previous version is kept until the file changes again
+pub struct TemplateCache {
+    registry: Registry,
+    markdown: MarkdownConfig,
+    templates: Mutex<Compiled>,
+}
+
+impl TemplateCache {
+    pub fn new(registry: Registry, markdown: MarkdownConfig) -> Self {
+        Self {
+            registry,
+            markdown,
+            templates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub async fn get<P: AsRef<Path>>(&self, path: P) -> Result<Arc<Template>> {
+        let path = path.as_ref();
+        let modified = fs::metadata(path).await?.modified()?;
+        if let Some((mtime, template)) = self.lock().get(path) {
+            if *mtime == modified {
+                return Ok(Arc::clone(template));
+            }
+        }
+        let template = match self.compile(path).await {
+            Ok(template) => Arc::new(template),
+            Err(err) => match self.lock().get_mut(path) {
+                Some((mtime, previous)) => {
+                    eprintln!(
+                        "keeping the previous version of {}: {}",
+                        path.display(),
+                        err
+                    );
+                    *mtime = modified;
+                    return Ok(Arc::clone(previous));
+                }
+                None => return Err(err),
+            },
+        };
+        self.lock()
+            .insert(path.to_path_buf(), (modified, Arc::clone(&template)));
+        Ok(template)
+    }
+
+    async fn compile(&self, path: &Path) -> Result<Template> {
+        let text = fs::read_to_string(path).await?;
+        Template::compile(path, &text, &self.registry, &self.markdown)
+    }
+
+    // compiles every cached template again, failing if one doesn't compile,
+    // nothing is swapped in until the result is put in place under `lock`
+    // `get` compiles a template again as soon as its file changes anyway, so
+    // this is only to fail a reload as a whole on a template that's broken
+    pub async fn recompile(&self) -> Result<Compiled> {
+        let paths: Vec<PathBuf> = self.lock().keys().cloned().collect();
+        let mut templates = HashMap::with_capacity(paths.len());
+        for path in paths {
+            let modified = match fs::metadata(&path).await {
+                Ok(metadata) => metadata.modified()?,
+                // compiled again on its next use, if it comes back
+                Err(_) => continue,
+            };
+            let template = self.compile(&path).await?;
+            templates.insert(path, (modified, Arc::new(template)));
+        }
+        Ok(templates)
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Compiled> {
+        self.templates
+            .lock()
+            .expect("template cache mutex poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(text: &str) -> Result<Template> {
+        Template::compile(
+            "test.html",
+            text,
+            &Registry::default(),
+            &MarkdownConfig::default(),
+        )
+    }
+
+    // the line and description of the error `text` fails to compile with
+    fn syntax_error(text: &str) -> (usize, String) {
+        match compile(text) {
+            Err(Error::TemplateSyntax(_, line, desc)) => (line, desc),
+            Err(err) => panic!("not a syntax error: {}", err),
+            Ok(template) => panic!("compiled: {:?}", template),
+        }
+    }
+
+    #[test]
+    fn unterminated_tags_point_to_their_line() {
+        assert_eq!(
+            syntax_error("{{{url"),
+            (1, "unterminated `{{{`".to_string())
+        );
+        let (line, _) = syntax_error("one\n{{{url}}}\nthree {{{ url");
+        assert_eq!(line, 3);
+        // lines inside of a tag count as well
+        let (line, _) = syntax_error("{{{l10n(title,\n  a: %1)}}}\n\n{{{url");
+        assert_eq!(line, 4);
+    }
+
+    #[test]
+    fn arguments_count_from_one() {
+        assert!(compile("{{{%1}}}").is_ok());
+        assert!(compile("{{{%0}}}").is_err());
+        assert!(compile("{{{maybe(%0)}}}").is_err());
+    }
+
+    #[test]
+    fn ifs_and_fors_nest() {
+        let template = compile(
+            "{{{if logged-in}}}\
+             {{{for a in articles(3)}}}\
+             {{{if not admin}}}{{{a.title}}}{{{else}}}x{{{end}}}\
+             {{{end}}}\
+             {{{end}}}",
+        )
+        .expect("couldn't compile");
+        let inner = Node::If(
+            Condition::Not(Box::new(Condition::Admin)),
+            vec![Node::Field("a".to_string(), Field::Title)],
+            vec![Node::Text("x".to_string())],
+        );
+        let outer = Node::If(
+            Condition::LoggedIn,
+            vec![Node::For("a".to_string(), 3, vec![inner])],
+            vec![],
+        );
+        assert_eq!(template.nodes, vec![outer]);
+    }
+
+    #[test]
+    fn loop_variables_end_with_their_for() {
+        assert!(compile("{{{for a in articles(1)}}}{{{a.path}}}{{{end}}}").is_ok());
+        assert!(compile("{{{for a in articles(1)}}}{{{end}}}{{{a.path}}}").is_err());
+        assert!(compile("{{{a.path}}}").is_err());
+    }
+
+    #[test]
+    fn else_belongs_to_an_if() {
+        let outside = "`else` outside of an `if`".to_string();
+        assert_eq!(syntax_error("{{{else}}}"), (1, outside.clone()));
+        assert_eq!(
+            syntax_error("{{{if admin}}}{{{else}}}\n{{{else}}}{{{end}}}"),
+            (2, outside)
+        );
+        assert_eq!(
+            syntax_error("{{{for a in articles(2)}}}{{{else}}}{{{end}}}"),
+            (1, "`else` inside of a `for`".to_string())
+        );
+    }
+
+    #[test]
+    fn unclosed_fors_point_to_their_start() {
+        assert_eq!(
+            syntax_error("a\n{{{for a in articles(2)}}}\n{{{a.title}}}\n"),
+            (2, "block is never closed with `end`".to_string())
+        );
+        assert_eq!(
+            syntax_error("{{{if admin}}}\n{{{if employee}}}{{{end}}}"),
+            (1, "block is never closed with `end`".to_string())
+        );
+        assert_eq!(
+            syntax_error("{{{end}}}"),
+            (1, "`end` without a block".to_string())
+        );
+    }
+
+    #[test]
+    fn unclosed_blocks_point_to_their_start() {
+        assert_eq!(
+            syntax_error("{{{extends /layout.html}}}\n{{{block main}}}\ntext"),
+            (2, "block is never closed with `end`".to_string())
+        );
+    }
+
+    #[test]
+    fn only_blocks_follow_extends() {
+        let outside = "only blocks may follow `extends`".to_string();
+        let page = "{{{extends /layout.html}}}\n\n{{{block main}}}x{{{end}}}\n";
+        assert_eq!(compile(page).expect("couldn't compile").nodes.len(), 1);
+        assert_eq!(
+            syntax_error("{{{extends /layout.html}}}\ntext"),
+            (1, outside.clone())
+        );
+        assert_eq!(
+            syntax_error("{{{extends /layout.html}}}\n{{{url}}}"),
+            (2, outside.clone())
+        );
+        assert_eq!(
+            syntax_error("{{{extends /layout.html}}}\n{{{if admin}}}{{{end}}}"),
+            (2, outside)
+        );
+        assert_eq!(
+            syntax_error("text\n{{{extends /layout.html}}}"),
+            (2, "`extends` has to come before anything else".to_string())
+        );
+    }
+
+    #[test]
+    fn pages_define_a_block_once() {
+        assert_eq!(
+            syntax_error(
+                "{{{extends /layout.html}}}\n\
+                 {{{block title}}}a{{{end}}}\n\
+                 {{{block title}}}b{{{end}}}"
+            ),
+            (3, "block `title` is defined twice".to_string())
+        );
+        // layouts fill in the same block in several places
+        assert!(compile("{{{block title}}}{{{end}}}{{{block title}}}{{{end}}}").is_ok());
+    }
+}
@@ -0,0 +1,593 @@
+use std::fmt::Write;
+use std::future::Future;
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use futures::{FutureExt, TryFutureExt};
+use tokio_postgres as psql;
+
+use crate::error::{Error, Result};
+use crate::i18n::Language;
+
+use super::registry::{Pattern, Registry};
+use super::{description, escape, full, preview, translated, Article, Output, RenderContext, User};
+
+fn login_links(lang: &Language, user: Option<&User>) -> String {
+    match user {
+        Some(user) => format!(
+            "<span class=\"float-right\"><a href=\"/auth/logout.html\">{}</a></span> \
+                        <span class=\"float-right\"><a href=\"/account/me.html\">{}: {}</a></span>",
+            &lang["logout"],
+            &lang["logged_in_as"],
+            escape(&user.username)
+        ),
+        None => format!(
+            "<span class=\"float-right\"><a href=\"/login.html\">{}</a></span> \
+                       <span class=\"float-right\"><a href=\"/create.html\">{}</a></span>",
+            &lang["login"], &lang["register"]
+        ),
+    }
+}
+
+async fn login(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    Ok(login_links(cx.lang, cx.user().await?.as_deref()))
+}
+
+// only employees are allowed to make new articles
+async fn editor(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    match cx.user().await? {
+        Some(user) if user.employee => Ok(format!(
+            "<span class=\"float-right\"><a href=\"/account/editor.html\">{}</a></span>",
+            &cx.lang["new_article"]
+        )),
+        _ => Err(Error::AuthorizationFailed),
+    }
+}
+
+async fn admin(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    match cx.user().await? {
+        Some(user) if user.admin => Ok(format!(
+            "<span class=\"float-right\"><a href=\"/account/admin.html\">{}</a></span>",
+            &cx.lang["admin_panel"]
+        )),
+        _ => Err(Error::AuthorizationFailed),
+    }
+}
+
+// drafts by id and title, nothing if there are none
+fn draft_select(drafts: &[(i32, Option<&str>)]) -> String {
+    if drafts.is_empty() {
+        return String::new();
+    }
+    let mut select = format!(
+        "<select oninput=\"load_draft()\" id=\"draft-select\" name=\"draft-select\" size=\"{}\">\n",
+        drafts.len().min(5).max(2)
+    );
+    for (value, title) in drafts {
+        let title = match title {
+            Some(title) if !title.is_empty() => escape(title),
+            _ => "&lt;untitled&gt;".to_string(),
+        };
+        write!(select, "<option value=\"{}\">{}</option>\n", value, title)
+            .expect("couldn't write to string");
+    }
+    write!(select, "</select>\n").expect("couldn't write to string");
+    select
+}
+
+async fn drafts(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    match cx.user().await? {
+        Some(user) => {
+            let drafts = cx
+                .client()
+                .await?
+                .query(
+                    "select id, path, title from drafts where drafts.author = $1",
+                    &[&user.id],
+                )
+                .await?;
+            let drafts: Vec<_> = drafts
+                .iter()
+                .map(|draft| (draft.get("id"), draft.get("title")))
+                .collect();
+            Ok(draft_select(&drafts))
+        }
+        None => Err(Error::AuthorizationFailed),
+    }
+}
+
+// every user with their roles, which admins can change
+fn user_table(lang: &Language, users: &[User]) -> String {
+    let mut select = format!("<table>\n");
+    write!(select, "<tr>\n").expect("couldn't write to string");
+    write!(select, "<th>UID</th>\n").expect("couldn't write to string");
+    write!(select, "<th>{}</th>\n", &lang["account_username"]).expect("couldn't write to string");
+    write!(select, "<th>{}</th>\n", &lang["account_firstname"]).expect("couldn't write to string");
+    write!(select, "<th>{}</th>\n", &lang["account_lastname"]).expect("couldn't write to string");
+    write!(select, "<th>{}</th>\n", &lang["account_email"]).expect("couldn't write to string");
+    write!(select, "<th>{}</th>\n", &lang["account_isemployee"]).expect("couldn't write to string");
+    write!(select, "<th>{}</th>\n", &lang["account_isadmin"]).expect("couldn't write to string");
+    write!(select, "</tr>\n").expect("couldn't write to string");
+    for user in users {
+        let id = user.id;
+        let isadmin = if user.admin {
+            "checked=\"checked\""
+        } else {
+            ""
+        };
+        let isemployee = if user.employee {
+            "checked=\"checked\""
+        } else {
+            ""
+        };
+        write!(select, "<tr>\n").expect("couldn't write to string");
+        write!(select, "<td>{}</td>\n", id).expect("couldn't write to string");
+        write!(select, "<td>{}</td>\n", escape(&user.username)).expect("couldn't write to string");
+        write!(
+            select,
+            "<td>{}</td>\n",
+            escape(user.firstname.as_deref().unwrap_or(""))
+        )
+        .expect("couldn't write to string");
+        write!(
+            select,
+            "<td>{}</td>\n",
+            escape(user.lastname.as_deref().unwrap_or(""))
+        )
+        .expect("couldn't write to string");
+        write!(
+            select,
+            "<td><a href=\"mailto:{0}\">{0}</a></td>\n",
+            escape(&user.email)
+        )
+        .expect("couldn't write to string");
+        write!(select, "<td><form><input type=\"checkbox\" {} oninput=\"make_employee(this, {})\"/></form></td>\n", isemployee, id).expect("couldn't write to string");
+        write!(select, "<td><form><input type=\"checkbox\" {} oninput=\"make_admin(this, {})\"/></form></td>\n", isadmin, id).expect("couldn't write to string");
+        write!(select, "</tr>\n").expect("couldn't write to string");
+    }
+    write!(select, "</table>\n").expect("couldn't write to string");
+    select
+}
+
+async fn admin_panel(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    let lang = cx.lang;
+    match cx.user().await? {
+        Some(user) if user.admin => {
+            // the roles are selected along with the users, not one query per user
+            let users = cx
+                .client()
+                .await?
+                .query(
+                    "select id, username, firstname, lastname, email, \
+                     exists(select 1 from employees where employees.uid = users.id) as employee, \
+                     exists(select 1 from admins where admins.uid = users.id) as admin \
+                     from users order by id",
+                    &[],
+                )
+                .await?;
+            let users: Vec<User> = users
+                .iter()
+                .map(|user| User {
+                    id: user.get("id"),
+                    username: user.get("username"),
+                    firstname: user.get("firstname"),
+                    lastname: user.get("lastname"),
+                    email: user.get("email"),
+                    employee: user.get("employee"),
+                    admin: user.get("admin"),
+                })
+                .collect();
+            Ok(user_table(lang, &users))
+        }
+        _ => Err(Error::AuthorizationFailed),
+    }
+}
+
+async fn sessions(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    let lang = cx.lang;
+    match cx.identity.identity() {
+        Some(current) => {
+            let sessions = cx.client().await?.query(
+                "select id, ip, user_agent, to_char(last_seen, 'yyyy-mm-dd hh24:mi') as last_seen \
+                 from sessions where expires > now() and uid = \
+                 (select uid from sessions where id = $1) order by last_seen desc",
+                &[&current]
+            ).await?;
+            let count = [("count", sessions.len().to_string())];
+            let mut select = format!("<p>{}</p>\n", lang.format("account_session_count", &count));
+            write!(select, "<table>\n").expect("couldn't write to string");
+            write!(select, "<tr>\n").expect("couldn't write to string");
+            write!(select, "<th>{}</th>\n", &lang["account_session_last_seen"])
+                .expect("couldn't write to string");
+            write!(select, "<th>{}</th>\n", &lang["account_session_ip"])
+                .expect("couldn't write to string");
+            write!(select, "<th>{}</th>\n", &lang["account_session_user_agent"])
+                .expect("couldn't write to string");
+            write!(select, "<th></th>\n").expect("couldn't write to string");
+            write!(select, "</tr>\n").expect("couldn't write to string");
+            for session in sessions {
+                let marker = if session.get::<_, &str>("id") == current {
+                    &lang["account_session_current"]
+                } else {
+                    ""
+                };
+                write!(select, "<tr>\n").expect("couldn't write to string");
+                write!(
+                    select,
+                    "<td>{}</td>\n",
+                    escape(session.get::<_, &str>("last_seen"))
+                )
+                .expect("couldn't write to string");
+                write!(
+                    select,
+                    "<td>{}</td>\n",
+                    escape(session.get::<_, Option<&str>>("ip").unwrap_or(""))
+                )
+                .expect("couldn't write to string");
+                write!(
+                    select,
+                    "<td>{}</td>\n",
+                    escape(session.get::<_, Option<&str>>("user_agent").unwrap_or(""))
+                )
+                .expect("couldn't write to string");
+                write!(select, "<td>{}</td>\n", marker).expect("couldn't write to string");
+                write!(select, "</tr>\n").expect("couldn't write to string");
+            }
+            write!(select, "</table>\n").expect("couldn't write to string");
+            Ok(select)
+        }
+        None => Err(Error::AuthorizationFailed),
+    }
+}
+
+// the fields of the logged in user `{{{me.field}}}` can refer to
+const ME_FIELDS: &[&str] = &["id", "username", "firstname", "lastname", "email", "pwhash"];
+
+// values of the logged in user, which are text and escaped by default
+fn me_field(user: Option<&User>, field: &str) -> Result<Output> {
+    let text = if field == "pwhash" {
+        "No passwords for you!".to_string()
+    } else {
+        match user {
+            Some(user) => match field {
+                "id" => user.id.to_string(),
+                "username" => user.username.clone(),
+                "firstname" => user.firstname.clone().unwrap_or_default(),
+                "lastname" => user.lastname.clone().unwrap_or_default(),
+                "email" => user.email.clone(),
+                _ => return Err(Error::InvalidPattern(format!("me.{}", field))),
+            },
+            None => "".to_string(),
+        }
+    };
+    Ok(Output::Text(text))
+}
+
+async fn me(cx: &RenderContext<'_>, field: &str) -> Result<Output> {
+    me_field(cx.user().await?.as_deref(), field)
+}
+
+// the article whose path is the N-th argument
+async fn positional(cx: &RenderContext<'_>, arg: &str) -> Result<Rc<Article>> {
+    let pos: usize = arg.parse()?;
+    let path = pos
+        .checked_sub(1)
+        .and_then(|idx| cx.args.get(idx))
+        .ok_or_else(|| Error::ResourceNotFound(format!("%{}", pos)))?;
+    cx.article(path).await
+}
+
+async fn article_positional(cx: &RenderContext<'_>, arg: &str) -> Result<String> {
+    full(cx.data, cx.lang, &*positional(cx, arg).await?).await
+}
+
+// for the title and meta tags of an article's page
+async fn meta_title(cx: &RenderContext<'_>, arg: &str) -> Result<Output> {
+    Ok(Output::Text(positional(cx, arg).await?.title.clone()))
+}
+
+async fn meta_author(cx: &RenderContext<'_>, arg: &str) -> Result<Output> {
+    let article = positional(cx, arg).await?;
+    Ok(Output::Text(article.author.clone().unwrap_or_default()))
+}
+
+async fn meta_description(cx: &RenderContext<'_>, arg: &str) -> Result<Output> {
+    let article = positional(cx, arg).await?;
+    Ok(Output::Text(description(cx.data, &article.path).await?))
+}
+
+// the address of the page in the language it's rendered in, for canonical links
+async fn url(cx: &RenderContext<'_>, _arg: &str) -> Result<Output> {
+    Ok(Output::Text(format!(
+        "{}{}?lang={}",
+        cx.origin,
+        cx.path,
+        cx.lang.code()
+    )))
+}
+
+// the page in every loaded language, and without one for everybody else
+async fn alternates(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    let catalog = cx.data.lang.current();
+    let mut codes: Vec<&str> = catalog.values().map(|lang| lang.code()).collect();
+    codes.sort();
+    let page = escape(&format!("{}{}", cx.origin, cx.path));
+    let mut links = String::new();
+    for code in codes {
+        write!(
+            links,
+            "<link rel=\"alternate\" hreflang=\"{0}\" href=\"{1}?lang={0}\"/>\n",
+            escape(code),
+            page
+        )
+        .expect("couldn't write to string");
+    }
+    write!(
+        links,
+        "<link rel=\"alternate\" hreflang=\"x-default\" href=\"{}\"/>\n",
+        page
+    )
+    .expect("couldn't write to string");
+    Ok(links)
+}
+
+// every article once, in the language of the page if it was translated to it
+async fn by_date(cx: &RenderContext<'_>) -> Result<Vec<psql::Row>> {
+    let query = format!(
+        "select * from ({}) as articles order by published",
+        translated("true")
+    );
+    Ok(cx
+        .client()
+        .await?
+        .query(&query[..], &[&cx.chain(), &cx.data.config.default_lang])
+        .await?)
+}
+
+// the article titled `title`, or the translation of it in the language of the page
+async fn by_title(cx: &RenderContext<'_>, title: &str) -> Result<psql::Row> {
+    let query = translated(
+        "coalesce(original, id) = \
+         (select coalesce(original, id) from articles where title = $3 limit 1)",
+    );
+    cx.client()
+        .await?
+        .query_opt(
+            &query[..],
+            &[&cx.chain(), &cx.data.config.default_lang, &title],
+        )
+        .await?
+        .ok_or_else(|| Error::ResourceNotFound(format!("article {}", title)))
+}
+
+async fn preview_latest(cx: &RenderContext<'_>, arg: &str) -> Result<String> {
+    let no: usize = arg.parse()?;
+    let rows = by_date(cx).await?;
+    let row = rows
+        .len()
+        .checked_sub(no)
+        .and_then(|no| rows.get(no))
+        .ok_or_else(|| Error::ResourceNotFound(format!("preview~{}", no)))?;
+    Ok(preview(cx.lang, &Article::from_row(cx, row).await?))
+}
+
+async fn article_latest(cx: &RenderContext<'_>, arg: &str) -> Result<String> {
+    let no: usize = arg.parse()?;
+    let rows = by_date(cx).await?;
+    match rows.len().checked_sub(no).and_then(|no| rows.get(no)) {
+        Some(row) => full(cx.data, cx.lang, &Article::from_row(cx, row).await?).await,
+        None => Ok(String::new()),
+    }
+}
+
+async fn preview_title(cx: &RenderContext<'_>, title: &str) -> Result<String> {
+    let row = by_title(cx, title).await?;
+    Ok(preview(cx.lang, &Article::from_row(cx, &row).await?))
+}
+
+async fn article_title(cx: &RenderContext<'_>, title: &str) -> Result<String> {
+    let row = by_title(cx, title).await?;
+    full(cx.data, cx.lang, &Article::from_row(cx, &row).await?).await
+}
+
+// the loaded languages to write an article in, the one of the page first
+async fn languages(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    let catalog = cx.data.lang.current();
+    let mut languages: Vec<(&str, &str)> = catalog
+        .values()
+        .map(|lang| (lang.code(), lang.language()))
+        .collect();
+    languages.sort();
+    let mut select = String::from("<select id=\"lang\" name=\"lang\">");
+    for (code, language) in languages {
+        let selected = if code == cx.lang.code() {
+            " selected"
+        } else {
+            ""
+        };
+        write!(
+            select,
+            "<option value=\"{}\"{}>{}</option>",
+            escape(code),
+            selected,
+            escape(language)
+        )
+        .expect("couldn't write to string");
+    }
+    select.push_str("</select>");
+    Ok(select)
+}
+
+// the articles a new one can be a translation of, translations aren't listed
+// since a translation of one is a translation of its original
+async fn originals(cx: &RenderContext<'_>, _arg: &str) -> Result<String> {
+    let rows = cx
+        .client()
+        .await?
+        .query(
+            "select id, title from articles where original is null order by title",
+            &[],
+        )
+        .await?;
+    let mut select = format!(
+        "<select id=\"original\" name=\"original\"><option value=\"\">{}</option>",
+        &cx.lang["editor_original_none"]
+    );
+    for row in rows {
+        write!(
+            select,
+            "<option value=\"{}\">{}</option>",
+            row.get::<_, i32>("id"),
+            escape(row.get::<_, &str>("title"))
+        )
+        .expect("couldn't write to string");
+    }
+    select.push_str("</select>");
+    Ok(select)
+}
+
+// markup we built, as opposed to text coming from users or the database
+fn html<'a, F>(render: F) -> LocalBoxFuture<'a, Result<Output>>
+where
+    F: Future<Output = Result<String>> + 'a,
+{
+    render.map_ok(Output::Html).boxed_local()
+}
+
+// an article or argument by number
+fn number(arg: &str) -> Result<()> {
+    arg.parse::<usize>()?;
+    Ok(())
+}
+
+fn known_field(arg: &str) -> Result<()> {
+    if ME_FIELDS.contains(&arg) {
+        Ok(())
+    } else {
+        Err(Error::InvalidPattern(format!("me.{}", arg)))
+    }
+}
+
+// the patterns every site has, see `Registry::default`
+pub fn register(registry: &mut Registry) {
+    registry.register("login", Pattern::new(|cx, arg| html(login(cx, arg))));
+    registry.register("editor", Pattern::new(|cx, arg| html(editor(cx, arg))));
+    registry.register("admin", Pattern::new(|cx, arg| html(admin(cx, arg))));
+    registry.register("drafts", Pattern::new(|cx, arg| html(drafts(cx, arg))));
+    registry.register(
+        "admin-panel",
+        Pattern::new(|cx, arg| html(admin_panel(cx, arg))),
+    );
+    registry.register("sessions", Pattern::new(|cx, arg| html(sessions(cx, arg))));
+    registry.register(
+        "me.",
+        Pattern::new(|cx, arg| me(cx, arg).boxed_local()).with_check(known_field),
+    );
+    registry.register(
+        "article%",
+        Pattern::new(|cx, arg| html(article_positional(cx, arg))).with_check(number),
+    );
+    registry.register(
+        "title%",
+        Pattern::new(|cx, arg| meta_title(cx, arg).boxed_local()).with_check(number),
+    );
+    registry.register(
+        "author%",
+        Pattern::new(|cx, arg| meta_author(cx, arg).boxed_local()).with_check(number),
+    );
+    registry.register(
+        "description%",
+        Pattern::new(|cx, arg| meta_description(cx, arg).boxed_local()).with_check(number),
+    );
+    registry.register("url", Pattern::new(|cx, arg| url(cx, arg).boxed_local()));
+    registry.register(
+        "alternates",
+        Pattern::new(|cx, arg| html(alternates(cx, arg))),
+    );
+    registry.register(
+        "preview~",
+        Pattern::new(|cx, arg| html(preview_latest(cx, arg))).with_check(number),
+    );
+    registry.register(
+        "article~",
+        Pattern::new(|cx, arg| html(article_latest(cx, arg))).with_check(number),
+    );
+    registry.register(
+        "preview ",
+        Pattern::new(|cx, arg| html(preview_title(cx, arg))),
+    );
+    registry.register(
+        "article ",
+        Pattern::new(|cx, arg| html(article_title(cx, arg))),
+    );
+    registry.register(
+        "languages",
+        Pattern::new(|cx, arg| html(languages(cx, arg))),
+    );
+    registry.register(
+        "originals",
+        Pattern::new(|cx, arg| html(originals(cx, arg))),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NASTY: &str = "<script>alert(1)</script>{{{admin-panel}}}";
+
+    fn lang() -> Language {
+        ron::de::from_str(r#"(code: "en", language: "English", t9n: {})"#)
+            .expect("couldn't parse language")
+    }
+
+    fn user() -> User {
+        User {
+            id: 1,
+            username: NASTY.to_string(),
+            firstname: Some(NASTY.to_string()),
+            lastname: Some(NASTY.to_string()),
+            email: NASTY.to_string(),
+            employee: true,
+            admin: false,
+        }
+    }
+
+    fn assert_escaped(html: &str) {
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(!html.contains("{{{"), "{}", html);
+        assert!(html.contains("&lt;script&gt;"), "{}", html);
+    }
+
+    #[test]
+    fn login_escapes_the_username() {
+        assert_escaped(&login_links(&lang(), Some(&user())));
+    }
+
+    #[test]
+    fn drafts_escape_titles() {
+        let html = draft_select(&[(1, Some(NASTY)), (2, None)]);
+        assert_escaped(&html);
+        assert!(html.contains("&lt;untitled&gt;"));
+        assert_eq!(draft_select(&[]), "");
+    }
+
+    #[test]
+    fn admin_panel_escapes_names_and_emails() {
+        let html = user_table(&lang(), &[user()]);
+        assert_escaped(&html);
+        assert_eq!(html.matches("&lt;script&gt;").count(), 5);
+    }
+
+    #[test]
+    fn me_is_text() {
+        let user = user();
+        for field in &["username", "firstname", "lastname", "email"] {
+            let output = me_field(Some(&user), field).expect("known field");
+            assert_escaped(&output.into_html());
+        }
+        let hash = me_field(Some(&user), "pwhash").expect("known field");
+        assert_eq!(hash.into_string(), "No passwords for you!");
+        assert!(me_field(Some(&user), "password").is_err());
+    }
+}
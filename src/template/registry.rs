@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use futures::future::LocalBoxFuture;
+
+use crate::error::Result;
+
+use super::{patterns, Output, RenderContext};
+
+// renders `{{{name}}}` or `{{{name<arg>}}}`, e.g. `{{{preview~3}}}` with the
+// argument `3`, when registered as `preview~`
+// markup goes into `Output::Html`, anything coming from users into `Output::Text`
+pub trait PatternHandler: Send + Sync {
+    // called when a template using the pattern is compiled, so a bad argument
+    // is reported with the file and line instead of failing every request
+    fn check(&self, _arg: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn render<'a>(
+        &'a self,
+        cx: &'a RenderContext<'a>,
+        arg: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Output>>;
+}
+
+// a handler made of a function, so a pattern doesn't need a type of its own, e.g.
+// `Pattern::new(|cx, arg| greet(cx, arg).boxed_local()).with_check(is_name)`
+pub struct Pattern<F> {
+    render: F,
+    check: fn(&str) -> Result<()>,
+}
+
+impl<F> Pattern<F>
+where
+    F: for<'a> Fn(&'a RenderContext<'a>, &'a str) -> LocalBoxFuture<'a, Result<Output>>
+        + Send
+        + Sync,
+{
+    pub fn new(render: F) -> Self {
+        Self {
+            render,
+            check: |_| Ok(()),
+        }
+    }
+
+    // see `PatternHandler::check`
+    pub fn with_check(mut self, check: fn(&str) -> Result<()>) -> Self {
+        self.check = check;
+        self
+    }
+}
+
+impl<F> PatternHandler for Pattern<F>
+where
+    F: for<'a> Fn(&'a RenderContext<'a>, &'a str) -> LocalBoxFuture<'a, Result<Output>>
+        + Send
+        + Sync,
+{
+    fn check(&self, arg: &str) -> Result<()> {
+        (self.check)(arg)
+    }
+
+    fn render<'a>(
+        &'a self,
+        cx: &'a RenderContext<'a>,
+        arg: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Output>> {
+        (self.render)(cx, arg)
+    }
+}
+
+// patterns that take an argument are registered with the character separating
+// it from the name, one of these
+const SEPARATORS: &[char] = &['.', '%', '~', ' '];
+
+pub struct Registry {
+    handlers: HashMap<String, Box<dyn PatternHandler>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    // replaces a handler that was registered under the same name before
+    pub fn register<H: PatternHandler + 'static>(&mut self, name: &str, handler: H) {
+        self.handlers.insert(name.to_string(), Box::new(handler));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn PatternHandler> {
+        self.handlers.get(name).map(|handler| &**handler)
+    }
+
+    // splits a tag into the name it's registered under and the argument
+    pub fn lookup<'a>(&self, tag: &'a str) -> Option<(&'a str, &'a str)> {
+        if self.handlers.contains_key(tag) {
+            return Some((tag, ""));
+        }
+        let idx = tag.find(SEPARATORS)?;
+        let (name, arg) = tag.split_at(idx + 1);
+        if self.handlers.contains_key(name) {
+            Some((name, arg))
+        } else {
+            None
+        }
+    }
+}
+
+// every built-in pattern, site specific ones are registered on top of this
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        patterns::register(&mut registry);
+        registry
+    }
+}
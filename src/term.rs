@@ -1,5 +1,12 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
 use pancurses::{Input, Window};
 
+use crate::error::{Error, Result};
+
 // TODO: handle arrow keys
 pub fn prompt(window: &Window, ps: Option<&str>, password: bool) -> Option<String> {
     if let Some(ps) = ps {
@@ -26,3 +33,33 @@ pub fn prompt(window: &Window, ps: Option<&str>, password: bool) -> Option<Strin
     pancurses::echo();
     Some(output)
 }
+
+fn strip_newline(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    line
+}
+
+// looks for the database password in `file`, then in `CIRCUS_DB_PASSWORD`, then on
+// stdin if it isn't a terminal, and only prompts for it as a last resort
+pub fn password<P: AsRef<Path>>(file: Option<P>) -> Result<String> {
+    if let Some(file) = file {
+        return Ok(strip_newline(fs::read_to_string(file)?));
+    }
+    if let Ok(password) = env::var("CIRCUS_DB_PASSWORD") {
+        return Ok(password);
+    }
+    if !atty::is(atty::Stream::Stdin) {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        return Ok(strip_newline(line));
+    }
+    let window = pancurses::initscr();
+    let password = prompt(&window, Some("Password: "), true);
+    pancurses::endwin();
+    password.ok_or_else(|| Error::Cmdline("password prompt cancelled".to_string()))
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use rustls::internal::pemfile;
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+use crate::config::{DatabaseTlsConfig, SslMode, TlsConfig};
+use crate::error::{Error, Result};
+
+fn certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = pemfile::certs(&mut reader)
+        .map_err(|()| Error::Tls(format!("invalid certificate in {}", path.display())))?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+// accepts both PKCS#8 (`BEGIN PRIVATE KEY`) and PKCS#1 (`BEGIN RSA PRIVATE KEY`) keys
+fn key(path: &Path) -> Result<PrivateKey> {
+    let invalid = |()| Error::Tls(format!("invalid private key in {}", path.display()));
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).map_err(invalid)?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).map_err(invalid)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| Error::Tls(format!("no private key in {}", path.display())))
+}
+
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig> {
+    let mut server = ServerConfig::new(NoClientAuth::new());
+    server
+        .set_single_cert(certs(&config.cert)?, key(&config.key)?)
+        .map_err(|err| Error::Tls(err.to_string()))?;
+    Ok(server)
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    // appended to the host if the HTTPS listener isn't on the default port
+    pub port: Option<u16>,
+}
+
+impl Redirect {
+    pub fn new(config: &TlsConfig) -> Self {
+        let port = config
+            .listen
+            .first()
+            .and_then(|addr| addr.rsplit(':').next())
+            .and_then(|port| port.parse().ok())
+            .filter(|&port| port != 443);
+        Self { port }
+    }
+}
+
+// answers every request on the plain HTTP listeners with a redirect to HTTPS
+pub async fn redirect(req: HttpRequest, redirect: web::Data<Redirect>) -> HttpResponse {
+    let host = req.connection_info().host().to_string();
+    // strip the port, keeping IPv6 literals like `[::1]` intact
+    let host = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => &host[..],
+    };
+    let location = match redirect.port {
+        Some(port) => format!("https://{}:{}{}", host, port, req.uri()),
+        None => format!("https://{}{}", host, req.uri()),
+    };
+    HttpResponse::PermanentRedirect()
+        .header(http::header::LOCATION, location)
+        .finish()
+}
+
+pub fn db_connector(config: &DatabaseTlsConfig) -> Result<MakeTlsConnector> {
+    let tls_err = |err: native_tls::Error| Error::Tls(err.to_string());
+    let mut builder = TlsConnector::builder();
+    // every certificate of the bundle, `from_pem` would only read the first
+    if let Some(ca) = &config.ca {
+        for cert in certs(ca)? {
+            let cert = native_tls::Certificate::from_der(&cert.0).map_err(tls_err)?;
+            builder.add_root_certificate(cert);
+        }
+    }
+    // like libpq, `prefer` and `require` verify the server once a CA is given
+    match (config.mode, &config.ca) {
+        (SslMode::Disable, _) | (SslMode::Prefer, None) | (SslMode::Require, None) => {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        (SslMode::Prefer, Some(_)) | (SslMode::Require, Some(_)) | (SslMode::VerifyCa, _) => {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        (SslMode::VerifyFull, _) => {}
+    }
+    Ok(MakeTlsConnector::new(builder.build().map_err(tls_err)?))
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
 
-use actix_http::HttpMessage;
 use actix_web::{get, http, web, HttpRequest, HttpResponse, Responder};
 use tokio::{fs, task::JoinHandle};
 use tokio_postgres::{self as psql, NoTls};
@@ -8,56 +9,107 @@ use actix_identity::Identity;
 use serde::{Serialize, Deserialize};
 use serde_json::json;
 
-use crate::error::Result;
-use crate::i18n::Language;
-use crate::template;
+use crate::config::{Config, SslMode};
+use crate::error::{Error, Result};
+use crate::i18n::{self, Lang, Languages};
+use crate::pool::{Pool, PooledClient};
+use crate::session;
+use crate::template::{self, RenderContext, TemplateCache};
+use crate::tls;
 
 pub struct ServerData<'a> {
-    pub(crate) client: psql::Client,
+    pub(crate) pool: Arc<Pool>,
+    pub(crate) config: Config,
     pub(crate) argon: argon2::Config<'a>,
-    pub(crate) lang: HashMap<String, Language>,
-    _handle: JoinHandle<()>,
+    pub(crate) lang: Arc<Languages>,
+    pub(crate) templates: Arc<TemplateCache>,
+}
+
+fn spawn<F>(conn: F) -> JoinHandle<()>
+where
+    F: Future<Output = std::result::Result<(), psql::Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            eprintln!("connection error: {}", e);
+        }
+    })
+}
+
+pub async fn connect(config: &Config, password: &str) -> Result<(psql::Client, JoinHandle<()>)> {
+    let dsn = config.dsn(password);
+    if config.database_tls.mode == SslMode::Disable {
+        let (client, conn) = psql::connect(&dsn, NoTls).await?;
+        Ok((client, spawn(conn)))
+    } else {
+        let tls = tls::db_connector(&config.database_tls)?;
+        let (client, conn) = psql::connect(&dsn, tls).await?;
+        Ok((client, spawn(conn)))
+    }
 }
 
 impl ServerData<'static> {
-    pub async fn new<S: Into<String>>(config: S, tls: NoTls) -> Result<Self> {
-        let (client, conn) = psql::connect(&config.into(), tls).await?;
-        let handle = tokio::spawn(async move {
-            if let Err(e) = conn.await {
-                eprintln!("connection error: {}", e);
-            }
-        });
-        let mut langs = HashMap::new();
-        let l10n = client.query("select code, path from l10n", &[]).await?;
-        for row in l10n {
-            let key = row.get::<_, &str>("code").to_string();
-            let path = row.get::<_, &str>("path");
-            let text = fs::read_to_string(path).await?;
-            let lang: Language = ron::de::from_str(&text)?;
-            langs.insert(key, lang);
+    pub fn new(
+        config: Config,
+        pool: Arc<Pool>,
+        lang: Arc<Languages>,
+        templates: Arc<TemplateCache>,
+    ) -> Self {
+        Self {
+            pool,
+            argon: config.argon2.to_argon2(),
+            config,
+            lang,
+            templates,
         }
-        Ok(Self {
-            client,
-            argon: argon2::Config::default(),
-            lang: langs,
-            _handle: handle,
-        })
+    }
+}
+
+// picks up changed language files and templates, all at once or not at all,
+// so a file that doesn't parse keeps the previous version of both around
+pub async fn reload(
+    config: &Config,
+    pool: &Pool,
+    lang: &Languages,
+    templates: &TemplateCache,
+) -> Result<()> {
+    let catalog = i18n::read(config, pool).await?;
+    let compiled = templates.recompile().await?;
+    // both are locked before either is swapped, so there's no moment in
+    // which the languages are new and the templates aren't
+    let mut current = lang.write();
+    let mut cached = templates.lock();
+    *current = Arc::new(catalog);
+    *cached = compiled;
+    Ok(())
+}
+
+impl<'a> ServerData<'a> {
+    pub async fn client(&self) -> Result<PooledClient> {
+        self.pool.get().await
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        reload(&self.config, &self.pool, &self.lang, &self.templates).await
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WordData {
     which: String,
+    // the rest of the query fills in placeholders, `count` picks the plural form
+    #[serde(flatten)]
+    args: HashMap<String, String>,
 }
 
 #[get("/style/{sheet}.css")]
 pub async fn stylesheet<'a>(
     _req: HttpRequest,
     _identity: Identity,
-    _data: web::Data<ServerData<'a>>,
+    data: web::Data<ServerData<'a>>,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let path = format!("public/style/{}.css", info);
+    let path = data.config.public.join(format!("style/{}.css", info));
     let sheet = fs::read_to_string(path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "text/css")
@@ -68,10 +120,10 @@ pub async fn stylesheet<'a>(
 pub async fn javascript<'a>(
     _req: HttpRequest,
     _identity: Identity,
-    _data: web::Data<ServerData<'a>>,
+    data: web::Data<ServerData<'a>>,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let path = format!("public/frontend/{}.js", info);
+    let path = data.config.public.join(format!("frontend/{}.js", info));
     let script = fs::read_to_string(path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "text/javascript")
@@ -82,10 +134,10 @@ pub async fn javascript<'a>(
 pub async fn wasm<'a>(
     _req: HttpRequest,
     _identity: Identity,
-    _data: web::Data<ServerData<'a>>,
+    data: web::Data<ServerData<'a>>,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let path = format!("public/frontend/{}.wasm", info);
+    let path = data.config.public.join(format!("frontend/{}.wasm", info));
     let script = fs::read(path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "application/wasm")
@@ -97,22 +149,14 @@ pub async fn articles<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    let path = "public/articles/template.html";
-    let mut body = fs::read_to_string(path).await?;
-    template::search_replace_recursive(
-        &identity,
-        &data.client,
-        &data.lang[&lang],
-        &mut body,
-        &[format!("articles/{}", info)],
-    )
-    .await?;
+    let path = data.config.public.join("articles/template.html");
+    let cx = RenderContext::new(&req, &identity, &data, &lang)
+        .await?
+        .with_args(vec![format!("articles/{}", info)]);
+    let body = template::render(&cx, path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "text/html")
         .body(body))
@@ -120,12 +164,13 @@ pub async fn articles<'a>(
 
 #[get("/api/whoami")]
 pub async fn api_whoami<'a>(
-    _req: HttpRequest,
+    req: HttpRequest,
     identity: Identity,
-    _data: web::Data<ServerData<'a>>,
+    data: web::Data<ServerData<'a>>,
 ) -> Result<impl Responder> {
+    let user = session::user(&req, &identity, &*data.client().await?).await?;
     let body = json!({
-        "username": identity.identity().unwrap_or_else(String::new)
+        "username": user.map(|user| user.username).unwrap_or_else(String::new)
     });
     let body = body.to_string();
     Ok(HttpResponse::Ok()
@@ -135,15 +180,12 @@ pub async fn api_whoami<'a>(
 
 #[get("/api/l10n")]
 pub async fn api_l10n<'a>(
-    req: HttpRequest,
+    _req: HttpRequest,
     _identity: Identity,
-    data: web::Data<ServerData<'a>>,
+    _data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    let body = serde_json::to_string(&data.lang[&lang])?;
+    let body = serde_json::to_string(&*lang)?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "application/json")
         .body(body))
@@ -152,16 +194,18 @@ pub async fn api_l10n<'a>(
 #[get("/api/t9n")]
 pub async fn api_t9n<'a>(
     word: web::Query<WordData>,
-    req: HttpRequest,
+    _req: HttpRequest,
     _identity: Identity,
-    data: web::Data<ServerData<'a>>,
+    _data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
+    let args: Vec<_> = word
+        .args
+        .iter()
+        .map(|(name, value)| (&name[..], value.clone()))
+        .collect();
     let body = json!({
-        "t9n": &data.lang[&lang][&word.which]
+        "t9n": lang.format(&word.which, &args)
     });
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "application/json")
@@ -173,16 +217,12 @@ pub async fn index<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
     info: web::Path<String>,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    let path = format!("public/{}.html", info);
-    let mut body = fs::read_to_string(path).await?;
-    template::search_replace_recursive(&identity, &data.client, &data.lang[&lang], &mut body, &[])
-        .await?;
+    let path = data.config.public.join(format!("{}.html", info));
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    let body = template::render(&cx, path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "text/html")
         .body(body))
@@ -193,16 +233,17 @@ pub async fn root<'a>(
     req: HttpRequest,
     identity: Identity,
     data: web::Data<ServerData<'a>>,
+    lang: Lang,
 ) -> Result<impl Responder> {
-    let lang = req
-        .cookie("lang")
-        .map(|cookie| cookie.value().to_string())
-        .unwrap_or_else(|| "de".to_string());
-    let path = "public/index.html";
-    let mut body = fs::read_to_string(path).await?;
-    template::search_replace_recursive(&identity, &data.client, &data.lang[&lang], &mut body, &[])
-        .await?;
+    let path = data.config.public.join("index.html");
+    let cx = RenderContext::new(&req, &identity, &data, &lang).await?;
+    let body = template::render(&cx, path).await?;
     Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "text/html")
         .body(body))
 }
+
+// so that unknown routes render `not-found.html` like every other error
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse> {
+    Err(Error::ResourceNotFound(req.path().to_string()))
+}